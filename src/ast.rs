@@ -1,20 +1,26 @@
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{alphanumeric1, multispace0, newline},
-    combinator::eof,
-    multi::many0,
+    combinator::opt,
+    error::{Error, ErrorKind},
     multi::separated_list0,
-    number::complete::double,
     sequence::{delimited, pair, preceded, terminated},
     IResult,
 };
 
+use super::{
+    diagnostic::{Diagnostic, Span},
+    lexer::{Lexeme, Token},
+};
+
+type Tokens<'a> = &'a [Lexeme];
+
+#[derive(Clone, PartialEq)]
 pub(super) enum Ast {
-    Root(Vec<Ast>),
+    Root(Vec<(Span, Ast)>),
     Value(f64),
     Idnt(String),
     Assign(String, Box<Ast>),
+    Let(String, Box<Ast>),
     Input,
     Print(Vec<Ast>),
     Add(Box<Ast>, Box<Ast>),
@@ -37,50 +43,88 @@ pub(super) enum Ast {
     WhileNot(Box<Ast>),
     If(Box<Ast>),
     IfNot(Box<Ast>),
+    ElseIf(Box<Ast>),
+    ElseIfNot(Box<Ast>),
+    Else,
     End,
+    Fn(String, Vec<String>),
+    /// `None` for a bare `return`, which gives back 0.
+    Return(Option<Box<Ast>>),
+    Call(String, Vec<Ast>),
+    /// Verbatim comment text, only produced when parsing with `keep_comments`.
+    Comment(String),
 }
 
-impl From<&str> for Ast {
-    fn from(s: &str) -> Ast {
-        Ast::program(s).unwrap().1
+impl TryFrom<&str> for Ast {
+    type Error = Vec<Diagnostic>;
+
+    fn try_from(s: &str) -> Result<Ast, Vec<Diagnostic>> {
+        Ast::parse(s, false)
     }
 }
 
-macro_rules! op {
-    ($name:ident, $op:literal, $op_name:ident) => {
-        fn $name(input: &str) -> IResult<&str, Ast> {
-            let (rest, value) = pair(
-                terminated(
-                    alt((
-                        delimited(tag("("), Ast::op, tag(")")),
-                        Ast::func,
-                        Ast::value,
-                        Ast::idnt,
-                    )),
-                    tag($op),
-                ),
-                alt((
-                    delimited(tag("("), Ast::op, tag(")")),
-                    Ast::func,
-                    Ast::value,
-                    Ast::idnt,
-                )),
-            )(input)?;
-            Ok((rest, Ast::$op_name(Box::new(value.0), Box::new(value.1))))
+type BinaryNode = fn(Box<Ast>, Box<Ast>) -> Ast;
+
+/// Binary operators from the loosest to the tightest binding, all left associative.
+const OPERATORS: [(Token, u8, BinaryNode); 8] = [
+    (Token::Eq, 0, Ast::Eql),
+    (Token::Gt, 0, Ast::Mor),
+    (Token::Lt, 0, Ast::Les),
+    (Token::Plus, 1, Ast::Add),
+    (Token::Minus, 1, Ast::Sub),
+    (Token::Star, 2, Ast::Mul),
+    (Token::Slash, 2, Ast::Div),
+    (Token::Percent, 2, Ast::Mod),
+];
+
+fn tag(token: Token) -> impl Fn(Tokens) -> IResult<Tokens, ()> {
+    move |input: Tokens| match input.split_first() {
+        Some((first, rest)) if first.token == token => Ok((rest, ())),
+        _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Tag))),
+    }
+}
+
+fn keyword(word: &'static str) -> impl Fn(Tokens) -> IResult<Tokens, ()> {
+    move |input: Tokens| match input.split_first() {
+        Some((first, rest)) if matches!(&first.token, Token::Idnt(name) if name == word) => {
+            Ok((rest, ()))
         }
-    };
+        _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Tag))),
+    }
+}
+
+fn name(input: Tokens) -> IResult<Tokens, String> {
+    match input.split_first() {
+        Some((
+            Lexeme {
+                token: Token::Idnt(name),
+                ..
+            },
+            rest,
+        )) => Ok((rest, name.clone())),
+        // labels and variables may be written with digits only, like `10:`
+        Some((
+            Lexeme {
+                token: Token::Number(_),
+                text,
+                ..
+            },
+            rest,
+        )) if text.chars().all(|c| c.is_ascii_alphanumeric()) => Ok((rest, text.clone())),
+        _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Alpha))),
+    }
 }
 
 macro_rules! assign_op {
-    ($name:ident, $op:literal, $op_name:ident) => {
-        fn $name(input: &str) -> IResult<&str, Ast> {
-            let (rest, value) = pair(terminated(alphanumeric1, tag($op)), Ast::exp)(input)?;
+    ($name:ident, $op:ident, $op_name:ident) => {
+        fn $name(input: Tokens) -> IResult<Tokens, Ast> {
+            let (rest, value) = pair(terminated(name, tag(Token::$op)), Ast::exp)(input)?;
             Ok((
                 rest,
                 Ast::Assign(
-                    value.0.to_string(),
+                    value.0.clone(),
                     Box::new(Ast::$op_name(
-                        Box::new(Ast::Idnt(value.0.to_string())),
+                        Box::new(Ast::Idnt(value.0)),
                         Box::new(value.1),
                     )),
                 ),
@@ -90,136 +134,289 @@ macro_rules! assign_op {
 }
 
 impl Ast {
-    fn program(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = terminated(many0(Ast::instruction), eof)(input)?;
-        Ok((rest, Ast::Root(value)))
-    }
-
-    fn instruction(input: &str) -> IResult<&str, Ast> {
-        delimited(
-            multispace0,
-            alt((
-                Ast::swap,
-                Ast::label,
-                Ast::goto_if,
-                Ast::goto_if_not,
-                Ast::goto,
-                Ast::_while,
-                Ast::while_not,
-                Ast::_if,
-                Ast::if_not,
-                Ast::end,
-                Ast::assign,
-                Ast::assign_op,
-                Ast::func,
-            )),
-            newline,
-        )(input)
-    }
-
-    fn exp(input: &str) -> IResult<&str, Ast> {
-        alt((Ast::op, Ast::func, Ast::value, Ast::idnt))(input)
-    }
-
-    fn swap(input: &str) -> IResult<&str, Ast> {
+    /// Parses a whole program, turning comments into `Ast::Comment` entries of the root
+    /// when `keep_comments` is set and dropping them otherwise.
+    pub(super) fn parse(source: &str, keep_comments: bool) -> Result<Ast, Vec<Diagnostic>> {
+        Ast::program(source, &Token::lex(source)?, keep_comments)
+    }
+
+    fn program(source: &str, tokens: Tokens, keep_comments: bool) -> Result<Ast, Vec<Diagnostic>> {
+        let mut instructions = Vec::new();
+        let mut diagnostics = Vec::new();
+        for line in tokens.split(|lexeme| lexeme.token == Token::Newline) {
+            let (comments, line): (Vec<_>, Vec<_>) = line
+                .iter()
+                .cloned()
+                .partition(|lexeme| matches!(lexeme.token, Token::Comment(_)));
+            let comments = comments
+                .into_iter()
+                .filter_map(|lexeme| match lexeme.token {
+                    Token::Comment(text) if keep_comments => {
+                        Some((lexeme.span, Ast::Comment(text)))
+                    }
+                    _ => None,
+                });
+            let (Some(first), Some(last)) = (line.first(), line.last()) else {
+                instructions.extend(comments);
+                continue;
+            };
+            let span = Span {
+                start: first.span.start,
+                end: last.span.end,
+            };
+            match Ast::instruction(&line) {
+                Ok(([], inst)) => {
+                    // comments keep their place before or after the statement
+                    let (before, after): (Vec<_>, Vec<_>) =
+                        comments.partition(|(comment, _)| comment.start < span.start);
+                    instructions.extend(before);
+                    instructions.push((span, inst));
+                    instructions.extend(after);
+                }
+                _ => {
+                    let text = source.lines().nth(span.start.line - 1).unwrap_or_default();
+                    diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("invalid instruction `{}`", text.trim()),
+                    ))
+                }
+            }
+        }
+        if diagnostics.is_empty() {
+            Ok(Ast::Root(instructions))
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    fn instruction(input: Tokens) -> IResult<Tokens, Ast> {
+        alt((
+            Ast::swap,
+            Ast::label,
+            Ast::goto_if,
+            Ast::goto_if_not,
+            Ast::goto,
+            Ast::_while,
+            Ast::while_not,
+            Ast::_if,
+            Ast::if_not,
+            Ast::else_if,
+            Ast::else_if_not,
+            Ast::_else,
+            Ast::end,
+            Ast::_fn,
+            Ast::_return,
+            Ast::_let,
+            Ast::assign,
+            Ast::assign_op,
+            Ast::func,
+            Ast::call,
+        ))(input)
+    }
+
+    fn exp(input: Tokens) -> IResult<Tokens, Ast> {
+        Ast::binary(input, 0)
+    }
+
+    fn binary(input: Tokens, min_prec: u8) -> IResult<Tokens, Ast> {
+        let (mut input, mut lhs) = Ast::unary(input)?;
+        while let Some((rest, prec, node)) = OPERATORS.iter().find_map(|(op, prec, node)| {
+            let (first, rest) = input.split_first()?;
+            let rest = Some(rest).filter(|_| first.token == *op && *prec >= min_prec)?;
+            Some((rest, *prec, node))
+        }) {
+            let (rest, rhs) = Ast::binary(rest, prec + 1)?;
+            lhs = node(Box::new(lhs), Box::new(rhs));
+            input = rest;
+        }
+        Ok((input, lhs))
+    }
+
+    fn unary(input: Tokens) -> IResult<Tokens, Ast> {
+        alt((
+            delimited(tag(Token::LParen), Ast::exp, tag(Token::RParen)),
+            Ast::func,
+            Ast::call,
+            Ast::value,
+            Ast::idnt,
+            Ast::neg,
+            preceded(tag(Token::Plus), Ast::unary),
+        ))(input)
+    }
+
+    fn neg(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = preceded(tag(Token::Minus), Ast::unary)(input)?;
+        match value {
+            Ast::Value(v) => Ok((rest, Ast::Value(-v))),
+            value => Ok((rest, Ast::Mul(Box::new(Ast::Value(-1.0)), Box::new(value)))),
+        }
+    }
+
+    fn swap(input: Tokens) -> IResult<Tokens, Ast> {
         let (rest, value) = pair(
-            preceded(tag("swap "), alphanumeric1),
-            preceded(tag(" and "), alphanumeric1),
+            preceded(keyword("swap"), name),
+            preceded(keyword("and"), name),
         )(input)?;
-        Ok((rest, Ast::Swap(value.0.to_string(), value.1.to_string())))
+        Ok((rest, Ast::Swap(value.0, value.1)))
     }
 
-    fn label(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = terminated(alphanumeric1, tag(":"))(input)?;
-        Ok((rest, Ast::Label(value.to_string())))
+    fn label(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = terminated(name, tag(Token::Colon))(input)?;
+        Ok((rest, Ast::Label(value)))
     }
 
-    fn goto(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = preceded(tag("goto "), alphanumeric1)(input)?;
-        Ok((rest, Ast::Goto(value.to_string())))
+    fn goto(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = preceded(keyword("goto"), name)(input)?;
+        Ok((rest, Ast::Goto(value)))
     }
 
-    fn goto_if(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = delimited(
-            tag("goto "),
-            pair(alphanumeric1, preceded(tag(" if "), Ast::exp)),
-            tag(" = 0"),
+    fn goto_if(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = preceded(
+            keyword("goto"),
+            pair(name, preceded(keyword("if"), Ast::exp)),
         )(input)?;
-        Ok((rest, Ast::GotoIf(value.0.to_string(), Box::new(value.1))))
+        // `= 0` is the loosest operator, so it always ends up at the top of the condition
+        match value.1 {
+            Ast::Eql(cond, zero) if matches!(*zero, Ast::Value(v) if v == 0.0) => {
+                Ok((rest, Ast::GotoIf(value.0, cond)))
+            }
+            _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Verify))),
+        }
     }
 
-    fn goto_if_not(input: &str) -> IResult<&str, Ast> {
+    fn goto_if_not(input: Tokens) -> IResult<Tokens, Ast> {
         let (rest, value) = delimited(
-            tag("goto "),
-            pair(alphanumeric1, preceded(tag(" if "), Ast::exp)),
-            tag(" != 0"),
+            keyword("goto"),
+            pair(name, preceded(keyword("if"), Ast::exp)),
+            pair(tag(Token::NotEq), tag(Token::Number(0.0))),
         )(input)?;
-        Ok((rest, Ast::GotoIfNot(value.0.to_string(), Box::new(value.1))))
+        Ok((rest, Ast::GotoIfNot(value.0, Box::new(value.1))))
     }
 
-    fn _while(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = delimited(tag("while not "), Ast::exp, tag(" {"))(input)?;
+    fn _while(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(
+            pair(keyword("while"), keyword("not")),
+            Ast::exp,
+            tag(Token::LBrace),
+        )(input)?;
         Ok((rest, Ast::While(Box::new(value))))
     }
 
-    fn while_not(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = delimited(tag("while "), Ast::exp, tag(" {"))(input)?;
+    fn while_not(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(keyword("while"), Ast::exp, tag(Token::LBrace))(input)?;
         Ok((rest, Ast::WhileNot(Box::new(value))))
     }
 
-    fn _if(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = delimited(tag("if not "), Ast::exp, tag(" {"))(input)?;
+    fn _if(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(
+            pair(keyword("if"), keyword("not")),
+            Ast::exp,
+            tag(Token::LBrace),
+        )(input)?;
         Ok((rest, Ast::If(Box::new(value))))
     }
 
-    fn if_not(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = delimited(tag("if "), Ast::exp, tag(" {"))(input)?;
+    fn if_not(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(keyword("if"), Ast::exp, tag(Token::LBrace))(input)?;
         Ok((rest, Ast::IfNot(Box::new(value))))
     }
 
-    fn end(input: &str) -> IResult<&str, Ast> {
-        let (rest, _) = tag("}")(input)?;
+    fn else_if(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(
+            pair(
+                pair(tag(Token::RBrace), keyword("else")),
+                pair(keyword("if"), keyword("not")),
+            ),
+            Ast::exp,
+            tag(Token::LBrace),
+        )(input)?;
+        Ok((rest, Ast::ElseIf(Box::new(value))))
+    }
+
+    fn else_if_not(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(
+            pair(pair(tag(Token::RBrace), keyword("else")), keyword("if")),
+            Ast::exp,
+            tag(Token::LBrace),
+        )(input)?;
+        Ok((rest, Ast::ElseIfNot(Box::new(value))))
+    }
+
+    fn _else(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, _) = pair(
+            pair(tag(Token::RBrace), keyword("else")),
+            tag(Token::LBrace),
+        )(input)?;
+        Ok((rest, Ast::Else))
+    }
+
+    fn end(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, _) = tag(Token::RBrace)(input)?;
         Ok((rest, Ast::End))
     }
 
-    fn assign(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = pair(terminated(alphanumeric1, tag(" = ")), Ast::exp)(input)?;
-        Ok((rest, Ast::Assign(value.0.to_string(), Box::new(value.1))))
+    fn _fn(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(
+            keyword("fn"),
+            pair(
+                name,
+                delimited(
+                    tag(Token::LParen),
+                    separated_list0(tag(Token::Comma), name),
+                    tag(Token::RParen),
+                ),
+            ),
+            tag(Token::LBrace),
+        )(input)?;
+        Ok((rest, Ast::Fn(value.0, value.1)))
     }
 
-    fn value(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = double(input)?;
-        Ok((rest, Ast::Value(value)))
+    fn _return(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = preceded(keyword("return"), opt(Ast::exp))(input)?;
+        Ok((rest, Ast::Return(value.map(Box::new))))
     }
 
-    fn idnt(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = alphanumeric1(input)?;
-        Ok((rest, Ast::Idnt(value.to_string())))
+    fn call(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = pair(
+            name,
+            delimited(
+                tag(Token::LParen),
+                separated_list0(tag(Token::Comma), Ast::exp),
+                tag(Token::RParen),
+            ),
+        )(input)?;
+        Ok((rest, Ast::Call(value.0, value.1)))
     }
 
-    fn op(input: &str) -> IResult<&str, Ast> {
-        alt((
-            Ast::op_add,
-            Ast::op_sub,
-            Ast::op_mul,
-            Ast::op_div,
-            Ast::op_mod,
-            Ast::op_eql,
-            Ast::op_mor,
-            Ast::op_les,
-        ))(input)
+    fn _let(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = pair(delimited(keyword("let"), name, tag(Token::Eq)), Ast::exp)(input)?;
+        Ok((rest, Ast::Let(value.0, Box::new(value.1))))
+    }
+
+    fn assign(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = pair(terminated(name, tag(Token::Eq)), Ast::exp)(input)?;
+        Ok((rest, Ast::Assign(value.0, Box::new(value.1))))
     }
 
-    op!(op_add, " + ", Add);
-    op!(op_sub, " - ", Sub);
-    op!(op_mul, " * ", Mul);
-    op!(op_div, " / ", Div);
-    op!(op_mod, " % ", Mod);
-    op!(op_eql, " = ", Eql);
-    op!(op_mor, " > ", Mor);
-    op!(op_les, " < ", Les);
+    fn value(input: Tokens) -> IResult<Tokens, Ast> {
+        match input.split_first() {
+            Some((
+                Lexeme {
+                    token: Token::Number(value),
+                    ..
+                },
+                rest,
+            )) => Ok((rest, Ast::Value(*value))),
+            _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Float))),
+        }
+    }
 
-    fn assign_op(input: &str) -> IResult<&str, Ast> {
+    fn idnt(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = name(input)?;
+        Ok((rest, Ast::Idnt(value)))
+    }
+
+    fn assign_op(input: Tokens) -> IResult<Tokens, Ast> {
         alt((
             Ast::assign_op_add,
             Ast::assign_op_sub,
@@ -229,49 +426,56 @@ impl Ast {
         ))(input)
     }
 
-    assign_op!(assign_op_add, " += ", Add);
-    assign_op!(assign_op_sub, " -= ", Sub);
-    assign_op!(assign_op_mul, " *= ", Mul);
-    assign_op!(assign_op_div, " /= ", Div);
-    assign_op!(assign_op_mod, " %= ", Mod);
+    assign_op!(assign_op_add, PlusEq, Add);
+    assign_op!(assign_op_sub, MinusEq, Sub);
+    assign_op!(assign_op_mul, StarEq, Mul);
+    assign_op!(assign_op_div, SlashEq, Div);
+    assign_op!(assign_op_mod, PercentEq, Mod);
 
-    fn func(input: &str) -> IResult<&str, Ast> {
+    fn func(input: Tokens) -> IResult<Tokens, Ast> {
         alt((Ast::inp, Ast::print, Ast::abs, Ast::max, Ast::min))(input)
     }
 
-    fn inp(input: &str) -> IResult<&str, Ast> {
-        let (rest, _) = tag("input()")(input)?;
+    fn inp(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, _) = pair(
+            keyword("input"),
+            pair(tag(Token::LParen), tag(Token::RParen)),
+        )(input)?;
         Ok((rest, Ast::Input))
     }
 
-    fn print(input: &str) -> IResult<&str, Ast> {
+    fn print(input: Tokens) -> IResult<Tokens, Ast> {
         let (rest, value) = delimited(
-            tag("print("),
-            separated_list0(tag(", "), Ast::exp),
-            tag(")"),
+            pair(keyword("print"), tag(Token::LParen)),
+            separated_list0(tag(Token::Comma), Ast::exp),
+            tag(Token::RParen),
         )(input)?;
         Ok((rest, Ast::Print(value)))
     }
 
-    fn abs(input: &str) -> IResult<&str, Ast> {
-        let (rest, value) = delimited(tag("abs("), Ast::exp, tag(")"))(input)?;
+    fn abs(input: Tokens) -> IResult<Tokens, Ast> {
+        let (rest, value) = delimited(
+            pair(keyword("abs"), tag(Token::LParen)),
+            Ast::exp,
+            tag(Token::RParen),
+        )(input)?;
         Ok((rest, Ast::Abs(Box::new(value))))
     }
 
-    fn max(input: &str) -> IResult<&str, Ast> {
+    fn max(input: Tokens) -> IResult<Tokens, Ast> {
         let (rest, (inner1, inner2)) = delimited(
-            tag("max("),
-            pair(terminated(Ast::exp, tag(", ")), Ast::exp),
-            tag(")"),
+            pair(keyword("max"), tag(Token::LParen)),
+            pair(terminated(Ast::exp, tag(Token::Comma)), Ast::exp),
+            tag(Token::RParen),
         )(input)?;
         Ok((rest, Ast::Max(Box::new(inner1), Box::new(inner2))))
     }
 
-    fn min(input: &str) -> IResult<&str, Ast> {
+    fn min(input: Tokens) -> IResult<Tokens, Ast> {
         let (rest, (inner1, inner2)) = delimited(
-            tag("min("),
-            pair(terminated(Ast::exp, tag(", ")), Ast::exp),
-            tag(")"),
+            pair(keyword("min"), tag(Token::LParen)),
+            pair(terminated(Ast::exp, tag(Token::Comma)), Ast::exp),
+            tag(Token::RParen),
         )(input)?;
         Ok((rest, Ast::Min(Box::new(inner1), Box::new(inner2))))
     }
@@ -1,13 +1,23 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
+    rc::Rc,
+};
 
-use super::ast::Ast;
+use super::{
+    ast::Ast,
+    definite,
+    diagnostic::{Diagnostic, Severity, Span},
+    Uninitialized,
+};
 
 #[derive(Debug, Clone)]
 pub(super) enum AstIndexed {
     Root(Vec<AstIndexed>),
     Value(f64),
-    Indx(u8),
-    Assign(u8, Box<AstIndexed>),
+    Indx(usize),
+    Assign(usize, Box<AstIndexed>),
     Input,
     Print(Vec<AstIndexed>),
     Add(Box<AstIndexed>, Box<AstIndexed>),
@@ -21,59 +31,287 @@ pub(super) enum AstIndexed {
     Eql(Box<AstIndexed>, Box<AstIndexed>),
     Mor(Box<AstIndexed>, Box<AstIndexed>),
     Les(Box<AstIndexed>, Box<AstIndexed>),
-    Swap(u8, u8),
+    Swap(usize, usize),
     Label(String),
     Goto(String),
     GotoIf(String, Box<AstIndexed>),
     GotoIfNot(String, Box<AstIndexed>),
+    /// name, parameters, return address, every slot the body writes to, body
+    Function(String, Vec<usize>, usize, Vec<usize>, Vec<AstIndexed>),
+    /// callee, arguments, scratch slot for the returned value
+    Call(String, Vec<AstIndexed>, usize),
+    Return(String, Box<AstIndexed>),
+    Drop(Box<AstIndexed>),
+    /// marks where the code of a source line starts
+    Line(Span),
 }
 
 enum Kind {
     While,
+    Fn,
     If,
+    /// `else if` branch, numbered by the `if` that started the chain
+    ElseIf(usize),
+    Else(usize),
+}
+
+/// Variables visible from the current block. Every block opens a scope and function bodies
+/// open one that hides everything outside of it. Each variable gets a slot of its own, the
+/// addresses are shared later on by `Ir::allocate`.
+struct MemMgr {
+    scopes: Vec<Scope>,
+    /// name of the variable in every slot
+    names: Vec<String>,
+}
+
+#[derive(Default)]
+struct Scope {
+    barrier: bool,
+    vars: HashMap<String, usize>,
+    closed: HashSet<String>,
+    /// every slot handed out while the scope was open
+    slots: Vec<usize>,
+}
+
+/// Hands out the numbers that keep the labels of every block apart.
+#[derive(Default)]
+struct LabelMgr {
+    len: usize,
+}
+
+/// Functions of the language itself, which a function of the program cannot take the name of.
+const BUILTINS: [&str; 5] = ["input", "print", "abs", "max", "min"];
+
+/// Labels of the main program or of one function. A `goto` only reaches the labels of its
+/// own function, or of the main program outside of every function.
+#[derive(Default)]
+struct Labels {
+    function: Option<String>,
+    defined: HashMap<String, Span>,
+    gotos: Vec<(String, Span)>,
 }
 
 struct State {
-    blocks: Vec<(Kind, Box<AstIndexed>, bool, usize)>,
-    counter: usize,
+    blocks: Vec<(Kind, Box<AstIndexed>, bool, usize, Span)>,
+    lblmgr: LabelMgr,
+    span: Span,
+    /// the main program first, then every function in order
+    labels: Vec<Labels>,
+    diagnostics: Vec<Diagnostic>,
+    signatures: HashMap<String, usize>,
+    function: Option<(String, Vec<usize>, Vec<AstIndexed>)>,
 }
 
-impl From<Ast> for AstIndexed {
-    fn from(ast: Ast) -> AstIndexed {
-        let memmgr = Rc::new(RefCell::new(HashMap::new()));
-        let state = Rc::new(RefCell::new(State {
-            blocks: Vec::new(),
-            counter: 0,
-        }));
-        AstIndexed::new(ast, memmgr, state)
+impl Default for MemMgr {
+    fn default() -> MemMgr {
+        MemMgr {
+            scopes: vec![Scope {
+                barrier: true,
+                ..Scope::default()
+            }],
+            names: Vec::new(),
+        }
     }
 }
 
+impl MemMgr {
+    fn open(&mut self, barrier: bool) {
+        self.scopes.push(Scope {
+            barrier,
+            ..Scope::default()
+        });
+    }
+
+    /// Drops the innermost scope and returns every slot it used.
+    fn close(&mut self) -> Vec<usize> {
+        if self.scopes.len() < 2 {
+            return Vec::new();
+        }
+        let Some(scope) = self.scopes.pop() else {
+            return Vec::new();
+        };
+        if let Some(parent) = self.scopes.last_mut() {
+            if !scope.barrier {
+                parent.closed.extend(scope.vars.into_keys());
+            }
+            parent.slots.extend(&scope.slots);
+        }
+        scope.slots
+    }
+
+    /// `Ok` with the slot of the closest visible variable, `Err(true)` if it only existed
+    /// in a scope that is already closed.
+    fn lookup(&self, name: &str) -> Result<usize, bool> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(n) = scope.vars.get(name) {
+                return Ok(*n);
+            }
+            if scope.closed.contains(name) {
+                return Err(true);
+            }
+            if scope.barrier {
+                break;
+            }
+        }
+        Err(false)
+    }
+
+    fn declare(&mut self, name: String) -> usize {
+        let n = self.alloc(&name);
+        self.scopes.iter_mut().for_each(|scope| scope.slots.push(n));
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.vars.insert(name, n);
+        }
+        n
+    }
+
+    /// Slot for a name in the outermost scope that is never part of a function frame, so
+    /// never one a closed scope gave back.
+    fn global(&mut self, name: &str) -> usize {
+        if let Some(n) = self.scopes[0].vars.get(name) {
+            return *n;
+        }
+        let n = self.alloc(name);
+        self.scopes[0].vars.insert(name.to_string(), n);
+        n
+    }
+
+    fn alloc(&mut self, name: &str) -> usize {
+        self.names.push(name.to_string());
+        self.names.len() - 1
+    }
+}
+
+impl LabelMgr {
+    fn alloc(&mut self) -> usize {
+        self.len += 1;
+        self.len - 1
+    }
+}
+
+/// Variables of the outermost scope with their slots, in the order of the slots.
+pub(super) type Globals = Vec<(String, usize)>;
+
+/// Name of a label added by the compiler. A `$` cannot be part of a label in the source, so
+/// the two never clash.
+pub(super) fn label(kind: &str, id: impl fmt::Display) -> String {
+    format!("{kind}${id}")
+}
+
 impl AstIndexed {
-    fn new(
+    /// Resolves the names of `ast`, giving back the warnings along with the result. `globals`
+    /// are variables assigned before the program starts, they get the first slots. Every
+    /// variable of the outermost scope that is assigned on all paths to the end comes back
+    /// with its slot.
+    pub(super) fn index(
         ast: Ast,
-        memmgr: Rc<RefCell<HashMap<String, u8>>>,
-        state: Rc<RefCell<State>>,
-    ) -> AstIndexed {
+        uninitialized: Uninitialized,
+        globals: &[String],
+    ) -> Result<(AstIndexed, Vec<Diagnostic>, Globals), Vec<Diagnostic>> {
+        let memmgr = Rc::new(RefCell::new(MemMgr::default()));
+        let entry = globals
+            .iter()
+            .map(|name| memmgr.borrow_mut().global(name))
+            .collect();
+        let state = Rc::new(RefCell::new(State {
+            blocks: Vec::new(),
+            lblmgr: LabelMgr::default(),
+            span: Span::default(),
+            labels: vec![Labels::default()],
+            diagnostics: Vec::new(),
+            signatures: HashMap::new(),
+            function: None,
+        }));
+        let mut ai = AstIndexed::new(ast, memmgr.clone(), state.clone());
+        let mut diagnostics = std::mem::take(&mut state.borrow_mut().diagnostics);
+        let mut assigned = None;
+        if let AstIndexed::Root(root) = &mut ai {
+            let names = &memmgr.borrow().names;
+            let (checked, exit) = definite::check(root, names, uninitialized, entry);
+            diagnostics.extend(checked);
+            assigned = exit;
+        }
+        let mut globals: Vec<_> = memmgr.borrow().scopes[0]
+            .vars
+            .iter()
+            .filter(|(name, n)| {
+                !name.starts_with('#') && assigned.as_ref().is_none_or(|exit| exit.contains(*n))
+            })
+            .map(|(name, n)| (name.clone(), *n))
+            .collect();
+        globals.sort_by_key(|(_, n)| *n);
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span);
+        let (errors, warnings) = diagnostics
+            .into_iter()
+            .partition::<Vec<_>, _>(|diagnostic| diagnostic.severity == Severity::Error);
+        if errors.is_empty() {
+            Ok((ai, warnings, globals))
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn new(ast: Ast, memmgr: Rc<RefCell<MemMgr>>, state: Rc<RefCell<State>>) -> AstIndexed {
         match ast {
             Ast::Root(inner) => {
-                let root = AstIndexed::Root(
-                    inner
-                        .into_iter()
-                        .map(|inst| AstIndexed::new(inst, memmgr.clone(), state.clone()))
-                        .collect(),
-                );
-                if !state.borrow().blocks.is_empty() {
-                    panic!("unclosed block");
+                for (span, inst) in &inner {
+                    let Ast::Fn(name, params) = inst else {
+                        continue;
+                    };
+                    let mut local_state = state.borrow_mut();
+                    let signatures = &mut local_state.signatures;
+                    if signatures.insert(name.clone(), params.len()).is_some() {
+                        local_state.diagnostics.push(Diagnostic::error(
+                            *span,
+                            format!("function `{name}` is defined more than once"),
+                        ));
+                    }
+                }
+                let mut root = Vec::new();
+                for (span, inst) in inner {
+                    state.borrow_mut().span = span;
+                    let inst = match inst {
+                        // an expression on its own line throws its value away
+                        Ast::Input | Ast::Abs(_) | Ast::Max(..) | Ast::Min(..) | Ast::Call(..) => {
+                            AstIndexed::Drop(Box::new(AstIndexed::new(
+                                inst,
+                                memmgr.clone(),
+                                state.clone(),
+                            )))
+                        }
+                        inst => AstIndexed::new(inst, memmgr.clone(), state.clone()),
+                    };
+                    match &mut state.borrow_mut().function {
+                        Some((.., body)) => body.extend([AstIndexed::Line(span), inst]),
+                        None => root.extend([AstIndexed::Line(span), inst]),
+                    }
+                }
+                let root = AstIndexed::Root(root);
+                let mut local_state = state.borrow_mut();
+                let State {
+                    blocks,
+                    labels,
+                    diagnostics,
+                    ..
+                } = &mut *local_state;
+                for (.., span) in blocks.drain(..) {
+                    diagnostics.push(Diagnostic::error(span, "unclosed block"));
+                }
+                for scope in labels.iter() {
+                    diagnostics.extend(AstIndexed::resolve(scope, labels));
                 }
                 root
             }
             Ast::Value(v) => AstIndexed::Value(v),
-            Ast::Idnt(name) => AstIndexed::Indx(AstIndexed::get(name, memmgr)),
+            Ast::Idnt(name) => AstIndexed::Indx(AstIndexed::get(name, memmgr, state)),
             Ast::Assign(var_name, inner) => {
-                let inner = Box::new(AstIndexed::new(*inner, memmgr.clone(), state));
+                let inner = Box::new(AstIndexed::new(*inner, memmgr.clone(), state.clone()));
                 AstIndexed::Assign(AstIndexed::assign(var_name, memmgr), inner)
             }
+            Ast::Let(var_name, inner) => {
+                let inner = Box::new(AstIndexed::new(*inner, memmgr.clone(), state));
+                AstIndexed::Assign(memmgr.borrow_mut().declare(var_name), inner)
+            }
             Ast::Input => AstIndexed::Input,
             Ast::Print(args) => AstIndexed::Print(
                 args.into_iter()
@@ -125,76 +363,232 @@ impl AstIndexed {
                 AstIndexed::assign(var1, memmgr.clone()),
                 AstIndexed::assign(var2, memmgr),
             ),
-            Ast::Label(name) => AstIndexed::Label(name),
-            Ast::Goto(name) => AstIndexed::Goto(name),
-            Ast::GotoIf(name, cond) => {
-                AstIndexed::GotoIf(name, Box::new(AstIndexed::new(*cond, memmgr, state)))
-            }
-            Ast::GotoIfNot(name, cond) => {
-                AstIndexed::GotoIfNot(name, Box::new(AstIndexed::new(*cond, memmgr, state)))
+            Ast::Label(name) => {
+                let mut local_state = state.borrow_mut();
+                let span = local_state.span;
+                let scope = local_state.scope();
+                let label = scope.name(&name);
+                if let Entry::Vacant(entry) = scope.defined.entry(name.clone()) {
+                    entry.insert(span);
+                } else {
+                    local_state.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("label `{name}` is defined more than once"),
+                    ));
+                }
+                AstIndexed::Label(label)
             }
+            Ast::Goto(name) => AstIndexed::Goto(AstIndexed::goto(name, &state)),
+            Ast::GotoIf(name, cond) => AstIndexed::GotoIf(
+                AstIndexed::goto(name, &state),
+                Box::new(AstIndexed::new(*cond, memmgr, state)),
+            ),
+            Ast::GotoIfNot(name, cond) => AstIndexed::GotoIfNot(
+                AstIndexed::goto(name, &state),
+                Box::new(AstIndexed::new(*cond, memmgr, state)),
+            ),
             Ast::While(cond) => {
+                let icond = AstIndexed::new(*cond, memmgr.clone(), state.clone());
+                memmgr.borrow_mut().open(false);
                 let mut local_state = state.borrow_mut();
-                let icond = AstIndexed::new(*cond, memmgr, state.clone());
-                let counter = local_state.counter;
-                let name = format!("while_{icond:?}true{}", counter);
-                let name_end = format!("end_while_{icond:?}true{}", counter);
-                local_state
-                    .blocks
-                    .push((Kind::While, Box::new(icond.clone()), true, counter));
-                local_state.counter += 1;
+                let span = local_state.span;
+                let counter = local_state.lblmgr.alloc();
+                let name = label("while", counter);
+                let name_end = label("end_while", counter);
+                local_state.blocks.push((
+                    Kind::While,
+                    Box::new(icond.clone()),
+                    true,
+                    counter,
+                    span,
+                ));
                 AstIndexed::Root(vec![
                     AstIndexed::GotoIfNot(name_end, Box::new(icond)),
                     AstIndexed::Label(name),
                 ])
             }
             Ast::WhileNot(cond) => {
+                let icond = AstIndexed::new(*cond, memmgr.clone(), state.clone());
+                memmgr.borrow_mut().open(false);
                 let mut local_state = state.borrow_mut();
-                let icond = AstIndexed::new(*cond, memmgr, state.clone());
-                let counter = local_state.counter;
-                let name = format!("while_{icond:?}false{}", counter);
-                let name_end = format!("end_while_{icond:?}false{}", counter);
-                local_state
-                    .blocks
-                    .push((Kind::While, Box::new(icond.clone()), false, counter));
-                local_state.counter += 1;
+                let span = local_state.span;
+                let counter = local_state.lblmgr.alloc();
+                let name = label("while", counter);
+                let name_end = label("end_while", counter);
+                local_state.blocks.push((
+                    Kind::While,
+                    Box::new(icond.clone()),
+                    false,
+                    counter,
+                    span,
+                ));
                 AstIndexed::Root(vec![
                     AstIndexed::GotoIf(name_end, Box::new(icond)),
                     AstIndexed::Label(name),
                 ])
             }
             Ast::If(cond) => {
+                let icond = AstIndexed::new(*cond, memmgr.clone(), state.clone());
+                memmgr.borrow_mut().open(false);
                 let mut local_state = state.borrow_mut();
-                let icond = AstIndexed::new(*cond, memmgr, state.clone());
-                let counter = local_state.counter;
-                let name_end = format!("end_if_{icond:?}true{}", counter);
+                let span = local_state.span;
+                let counter = local_state.lblmgr.alloc();
+                let name_end = label("end_if", counter);
                 local_state
                     .blocks
-                    .push((Kind::If, Box::new(icond.clone()), true, counter));
-                local_state.counter += 1;
+                    .push((Kind::If, Box::new(icond.clone()), true, counter, span));
                 AstIndexed::GotoIfNot(name_end, Box::new(icond))
             }
             Ast::IfNot(cond) => {
+                let icond = AstIndexed::new(*cond, memmgr.clone(), state.clone());
+                memmgr.borrow_mut().open(false);
                 let mut local_state = state.borrow_mut();
-                let icond = AstIndexed::new(*cond, memmgr, state.clone());
-                let counter = local_state.counter;
-                let name_end = format!("end_if_{icond:?}false{}", counter);
+                let span = local_state.span;
+                let counter = local_state.lblmgr.alloc();
+                let name_end = label("end_if", counter);
                 local_state
                     .blocks
-                    .push((Kind::If, Box::new(icond.clone()), false, counter));
-                local_state.counter += 1;
+                    .push((Kind::If, Box::new(icond.clone()), false, counter, span));
                 AstIndexed::GotoIf(name_end, Box::new(icond))
             }
+            Ast::ElseIf(cond) => AstIndexed::else_if(*cond, true, memmgr, state),
+            Ast::ElseIfNot(cond) => AstIndexed::else_if(*cond, false, memmgr, state),
+            Ast::Else => {
+                let Some((chain, root)) = AstIndexed::else_branch(&memmgr, &state) else {
+                    return AstIndexed::Root(Vec::new());
+                };
+                memmgr.borrow_mut().open(false);
+                let mut local_state = state.borrow_mut();
+                let span = local_state.span;
+                local_state.blocks.push((
+                    Kind::Else(chain),
+                    Box::new(AstIndexed::Root(Vec::new())),
+                    true,
+                    chain,
+                    span,
+                ));
+                AstIndexed::Root(root)
+            }
+            Ast::Fn(name, params) => {
+                let mut local_state = state.borrow_mut();
+                let span = local_state.span;
+                let counter = local_state.lblmgr.alloc();
+                if local_state.function.is_some() || !local_state.blocks.is_empty() {
+                    local_state.diagnostics.push(Diagnostic::error(
+                        span,
+                        "functions can only be defined at the top level",
+                    ));
+                    // keep the closing brace balanced
+                    memmgr.borrow_mut().open(false);
+                    let block = Box::new(AstIndexed::Root(Vec::new()));
+                    local_state
+                        .blocks
+                        .push((Kind::If, block, true, counter, span));
+                    return AstIndexed::Root(Vec::new());
+                }
+                if BUILTINS.contains(&name.as_str()) {
+                    local_state.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("`{name}` is a built-in function and cannot be defined again"),
+                    ));
+                }
+                let mut seen = HashSet::new();
+                for param in params.iter().filter(|param| !seen.insert(*param)) {
+                    local_state.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("parameter `{param}` of function `{name}` is given more than once"),
+                    ));
+                }
+                local_state.labels.push(Labels {
+                    function: Some(name.clone()),
+                    ..Labels::default()
+                });
+                local_state.function = Some((name, Vec::new(), Vec::new()));
+                local_state.blocks.push((
+                    Kind::Fn,
+                    Box::new(AstIndexed::Root(Vec::new())),
+                    true,
+                    counter,
+                    span,
+                ));
+                let mut local_memmgr = memmgr.borrow_mut();
+                local_memmgr.open(true);
+                let params = params
+                    .into_iter()
+                    .map(|param| local_memmgr.declare(param))
+                    .collect();
+                local_memmgr.declare("#".to_string());
+                if let Some(function) = &mut local_state.function {
+                    function.1 = params;
+                }
+                AstIndexed::Root(Vec::new())
+            }
+            Ast::Return(value) => {
+                let value = value.map_or(Ast::Value(0.0), |value| *value);
+                let value = AstIndexed::new(value, memmgr, state.clone());
+                let mut local_state = state.borrow_mut();
+                if let Some((name, ..)) = &local_state.function {
+                    AstIndexed::Return(name.clone(), Box::new(value))
+                } else {
+                    let span = local_state.span;
+                    local_state
+                        .diagnostics
+                        .push(Diagnostic::error(span, "`return` outside of a function"));
+                    AstIndexed::Root(Vec::new())
+                }
+            }
+            Ast::Call(name, args) => {
+                let args: Vec<_> = args
+                    .into_iter()
+                    .map(|arg| AstIndexed::new(arg, memmgr.clone(), state.clone()))
+                    .collect();
+                let mut local_state = state.borrow_mut();
+                let span = local_state.span;
+                match local_state.signatures.get(&name) {
+                    Some(&arity) if arity != args.len() => {
+                        local_state.diagnostics.push(Diagnostic::error(
+                            span,
+                            format!(
+                                "function `{name}` takes {arity} arguments but {} were given",
+                                args.len()
+                            ),
+                        ))
+                    }
+                    Some(_) => (),
+                    None => local_state.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("unknown function `{name}`"),
+                    )),
+                }
+                AstIndexed::Call(name, args, memmgr.borrow_mut().global("#"))
+            }
+            Ast::Comment(_) => AstIndexed::Root(Vec::new()),
             Ast::End => {
                 let mut local_state = state.borrow_mut();
-                let (kind, cond, ty, counter) = local_state
-                    .blocks
-                    .pop()
-                    .expect("there are more ends then blocks");
+                let Some((kind, cond, ty, counter, _)) = local_state.blocks.pop() else {
+                    let span = local_state.span;
+                    local_state
+                        .diagnostics
+                        .push(Diagnostic::error(span, "there are more ends then blocks"));
+                    return AstIndexed::Root(Vec::new());
+                };
+                // return address of the function, in case that is what gets closed
+                let ra = memmgr.borrow().lookup("#");
+                let mut frame = memmgr.borrow_mut().close();
                 match kind {
+                    Kind::Fn => {
+                        let (Some((name, params, body)), Ok(ra)) =
+                            (local_state.function.take(), ra)
+                        else {
+                            return AstIndexed::Root(Vec::new());
+                        };
+                        frame.sort();
+                        frame.dedup();
+                        AstIndexed::Function(name, params, ra, frame, body)
+                    }
                     Kind::While => {
-                        let name = format!("while_{cond:?}{ty}{counter}");
-                        let name_end = format!("end_while_{cond:?}{ty}{counter}");
+                        let name = label("while", counter);
+                        let name_end = label("end_while", counter);
                         if ty {
                             AstIndexed::Root(vec![
                                 AstIndexed::GotoIf(name, cond),
@@ -207,32 +601,205 @@ impl AstIndexed {
                             ])
                         }
                     }
-                    Kind::If => {
-                        let name_end = format!("end_if_{cond:?}{ty}{counter}");
-                        AstIndexed::Label(name_end)
+                    Kind::If => AstIndexed::Label(label("end_if", counter)),
+                    Kind::ElseIf(chain) => {
+                        let name_end = label("end_if", counter);
+                        AstIndexed::Root(vec![
+                            AstIndexed::Label(name_end),
+                            AstIndexed::Label(label("end_else", chain)),
+                        ])
                     }
+                    Kind::Else(chain) => AstIndexed::Label(label("end_else", chain)),
                 }
             }
         }
     }
 
-    fn assign(name: String, memmgr: Rc<RefCell<HashMap<String, u8>>>) -> u8 {
-        let mut local_memmgr = memmgr.borrow_mut();
-        if let Some(n) = local_memmgr.get(&name) {
-            *n
+    fn else_if(
+        cond: Ast,
+        ty: bool,
+        memmgr: Rc<RefCell<MemMgr>>,
+        state: Rc<RefCell<State>>,
+    ) -> AstIndexed {
+        let Some((chain, mut root)) = AstIndexed::else_branch(&memmgr, &state) else {
+            return AstIndexed::Root(Vec::new());
+        };
+        let icond = AstIndexed::new(cond, memmgr.clone(), state.clone());
+        memmgr.borrow_mut().open(false);
+        let mut local_state = state.borrow_mut();
+        let span = local_state.span;
+        let counter = local_state.lblmgr.alloc();
+        let name_end = label("end_if", counter);
+        local_state.blocks.push((
+            Kind::ElseIf(chain),
+            Box::new(icond.clone()),
+            ty,
+            counter,
+            span,
+        ));
+        root.push(if ty {
+            AstIndexed::GotoIfNot(name_end, Box::new(icond))
         } else {
-            let n = local_memmgr.len() as u8;
-            local_memmgr.insert(name, n);
-            n
+            AstIndexed::GotoIf(name_end, Box::new(icond))
+        });
+        AstIndexed::Root(root)
+    }
+
+    /// Closes the `if` (or `else if`) branch continued by an `else`, returning the number
+    /// of the chain and the jumps that skip the remaining branches.
+    fn else_branch(
+        memmgr: &Rc<RefCell<MemMgr>>,
+        state: &Rc<RefCell<State>>,
+    ) -> Option<(usize, Vec<AstIndexed>)> {
+        let mut local_state = state.borrow_mut();
+        match local_state.blocks.pop() {
+            Some((kind @ (Kind::If | Kind::ElseIf(_)), _, _, counter, _)) => {
+                memmgr.borrow_mut().close();
+                let chain = match kind {
+                    Kind::ElseIf(chain) => chain,
+                    _ => counter,
+                };
+                Some((
+                    chain,
+                    vec![
+                        AstIndexed::Goto(label("end_else", chain)),
+                        AstIndexed::Label(label("end_if", counter)),
+                    ],
+                ))
+            }
+            block => {
+                local_state.blocks.extend(block);
+                let span = local_state.span;
+                local_state
+                    .diagnostics
+                    .push(Diagnostic::error(span, "`else` without `if`"));
+                None
+            }
         }
     }
 
-    fn get(name: String, memmgr: Rc<RefCell<HashMap<String, u8>>>) -> u8 {
-        let local_memmgr = memmgr.borrow_mut();
-        if let Some(n) = local_memmgr.get(&name) {
-            *n
-        } else {
-            panic!("uninitialized variable: {name}")
+    fn assign(name: String, memmgr: Rc<RefCell<MemMgr>>) -> usize {
+        let mut local_memmgr = memmgr.borrow_mut();
+        match local_memmgr.lookup(&name) {
+            Ok(n) => n,
+            Err(_) => local_memmgr.declare(name),
+        }
+    }
+
+    /// Slot of a variable that is read. A name that is not known yet gets declared, whether
+    /// it is assigned before the read is up to `definite::check`.
+    fn get(name: String, memmgr: Rc<RefCell<MemMgr>>, state: Rc<RefCell<State>>) -> usize {
+        let found = memmgr.borrow().lookup(&name);
+        match found {
+            Ok(n) => n,
+            Err(false) => memmgr.borrow_mut().declare(name),
+            Err(true) => {
+                let mut local_state = state.borrow_mut();
+                let span = local_state.span;
+                local_state.diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("use of variable `{name}` out of scope"),
+                ));
+                // a slot of its own, so the read is not reported again
+                memmgr.borrow_mut().alloc("#")
+            }
+        }
+    }
+
+    /// The closest of `labels` to a misspelled `name`, if any is close enough.
+    fn suggest<'a>(name: &str, labels: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+        labels
+            .map(|label| (AstIndexed::edit_distance(name, label), label.as_str()))
+            .filter(|(distance, label)| *distance <= label.len().max(name.len()).div_ceil(3))
+            .min()
+            .map(|(_, label)| label)
+    }
+
+    /// Levenshtein distance between two names.
+    fn edit_distance(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.chars().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = (above + 1)
+                    .min(row[j] + 1)
+                    .min(diagonal + usize::from(ca != *cb));
+                diagonal = above;
+            }
         }
+        row[b.len()]
+    }
+
+    /// Records a jump to the label `name` and gives back the name of the label in the code.
+    fn goto(name: String, state: &Rc<RefCell<State>>) -> String {
+        let mut local_state = state.borrow_mut();
+        let span = local_state.span;
+        let scope = local_state.scope();
+        let label = scope.name(&name);
+        scope.gotos.push((name, span));
+        label
+    }
+
+    /// Errors for the jumps of `scope` that go nowhere or leave it, and warnings for its
+    /// labels nobody jumps to.
+    fn resolve(scope: &Labels, every: &[Labels]) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for (name, span) in &scope.gotos {
+            if scope.defined.contains_key(name) {
+                continue;
+            }
+            let elsewhere = every.iter().find(|other| other.defined.contains_key(name));
+            let message = match (elsewhere.map(|other| &other.function), &scope.function) {
+                (Some(Some(function)), _) => {
+                    format!("cannot jump to label `{name}` inside of function `{function}`")
+                }
+                (Some(None), Some(function)) => {
+                    format!("cannot jump to label `{name}` out of function `{function}`")
+                }
+                _ => match AstIndexed::suggest(name, scope.defined.keys()) {
+                    Some(label) => format!("unknown label `{name}`, did you mean `{label}`?"),
+                    None => format!("unknown label `{name}`"),
+                },
+            };
+            diagnostics.push(Diagnostic::error(*span, message));
+        }
+        let targets: HashSet<_> = scope.gotos.iter().map(|(name, _)| name).collect();
+        let mut unused: Vec<_> = scope
+            .defined
+            .iter()
+            .filter(|(name, _)| !targets.contains(name))
+            .collect();
+        unused.sort_by_key(|(_, span)| **span);
+        for (name, span) in unused {
+            diagnostics.push(Diagnostic::warning(
+                *span,
+                format!("label `{name}` is never jumped to"),
+            ));
+        }
+        diagnostics
+    }
+}
+
+impl Labels {
+    /// Name of a label of the source in the code, made unique to the function it is in.
+    fn name(&self, label: &str) -> String {
+        match &self.function {
+            Some(function) => format!("{function}::{label}"),
+            None => label.to_string(),
+        }
+    }
+}
+
+impl State {
+    /// Labels of the function being defined, or of the main program outside of one.
+    fn scope(&mut self) -> &mut Labels {
+        let i = match self.function {
+            Some(_) => self.labels.len() - 1,
+            None => 0,
+        };
+        &mut self.labels[i]
     }
 }
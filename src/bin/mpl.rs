@@ -0,0 +1,129 @@
+//! Interactive prompt, `mpl [-O0|-O1|-O2]`. Variables stay around from one entry to the next.
+//! `:history` only lists the entries; there is no recalling or editing them, as the prompt
+//! reads plain lines of stdin.
+
+use std::io::{self, Write};
+
+use mpl_sc_lib::{OptLevel, Parser, Session};
+
+const HELP: &str = "\
+entries run as soon as every `{` is closed, a bare expression is printed
+:vars     show the variables
+:asm      show the code of the last entry that compiled
+:history  show the entries so far, without a way to run them again
+:reset    forget every variable
+:help     show this
+:quit     leave, like end of input";
+
+fn main() {
+    let opt = match std::env::args().nth(1).as_deref() {
+        None | Some("-O0") => OptLevel::O0,
+        Some("-O1") => OptLevel::O1,
+        Some("-O2") => OptLevel::O2,
+        Some(arg) => {
+            eprintln!("unknown argument `{arg}`, usage: mpl [-O0|-O1|-O2]");
+            std::process::exit(2);
+        }
+    };
+    let mut session = Session::new(opt);
+    let mut history: Vec<String> = Vec::new();
+    let mut last = None;
+    let mut entry = String::new();
+    while let Some(line) = read_line(if entry.is_empty() { "mpl> " } else { "...> " }) {
+        if entry.is_empty() {
+            match line.trim() {
+                "" => continue,
+                ":vars" => {
+                    for (name, val) in session.vars() {
+                        println!("{name} = {val}");
+                    }
+                    continue;
+                }
+                ":asm" => {
+                    match &last {
+                        Some(program) => print!("{program}"),
+                        None => eprintln!("nothing compiled yet"),
+                    }
+                    continue;
+                }
+                ":history" => {
+                    for (i, entry) in history.iter().enumerate() {
+                        print!("[{}]\n{entry}", i + 1);
+                    }
+                    continue;
+                }
+                ":reset" => {
+                    session.reset();
+                    continue;
+                }
+                ":help" => {
+                    println!("{HELP}");
+                    continue;
+                }
+                ":quit" => break,
+                command if command.starts_with(':') => {
+                    eprintln!("unknown command `{command}`, try :help");
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        entry += &line;
+        entry.push('\n');
+        if Parser::is_incomplete(&entry) {
+            continue;
+        }
+        let entry = std::mem::take(&mut entry);
+        history.push(entry.clone());
+        if let Some(program) = run(&mut session, &entry) {
+            last = Some(program);
+        }
+    }
+}
+
+/// Runs an entry, or prints its value when it is a bare expression, and gives back the
+/// program it compiled to.
+fn run(session: &mut Session, entry: &str) -> Option<Parser> {
+    let source = if Parser::is_expression(entry) {
+        format!("print({})\n", entry.trim())
+    } else {
+        entry.to_string()
+    };
+    let mut program = match session.compile(&source) {
+        Ok(program) => program,
+        Err(errors) => {
+            errors.iter().for_each(|error| eprintln!("{error}"));
+            return None;
+        }
+    };
+    let res = session.eval_program(&mut program, read_input, |val| println!("{val}"));
+    session
+        .warnings()
+        .iter()
+        .for_each(|warning| eprintln!("{warning}"));
+    if let Err(error) = res {
+        eprintln!("{error}");
+    }
+    Some(program)
+}
+
+/// Asks for the value of an `input()` until it gets a number.
+fn read_input() -> Option<f64> {
+    loop {
+        let line = read_line("input? ")?;
+        match line.trim().parse() {
+            Ok(val) => return Some(val),
+            Err(_) => eprintln!("`{}` is not a number", line.trim()),
+        }
+    }
+}
+
+fn read_line(prompt: &str) -> Option<String> {
+    print!("{prompt}");
+    io::stdout().flush().ok()?;
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+    }
+}
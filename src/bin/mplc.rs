@@ -0,0 +1,200 @@
+//! Compiler driver, `mplc [-O0|-O1|-O2] [--emit KIND] [--run] [--input 1,2,3] FILE`.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    process::ExitCode,
+};
+
+use mpl_sc_lib::{OptLevel, Parser};
+
+const USAGE: &str = "\
+usage: mplc [-O0|-O1|-O2] [--emit KIND] [--run] [--input 1,2,3] FILE
+  FILE           source file, `-` for stdin, which leaves --input to --run
+  -O0, -O1, -O2  optimisation level, -O0 by default
+  --emit KIND    print the program as ast, indexed, ir, code, asm or bytecode,
+                 asm when there is no --run
+  --run          run the program, taking input() from stdin
+  --input LIST   run the program on the comma separated numbers instead";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    Ast,
+    Indexed,
+    Ir,
+    Code,
+    Asm,
+    Bytecode,
+}
+
+struct Args {
+    path: String,
+    opt: OptLevel,
+    emit: Option<Emit>,
+    run: bool,
+    inputs: Option<Vec<f64>>,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) if error.is_empty() => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("{error}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let mut source = String::new();
+    let read = match args.path.as_str() {
+        "-" => io::stdin().read_to_string(&mut source).map(drop),
+        path => std::fs::read_to_string(path).map(|text| source = text),
+    };
+    if let Err(error) = read {
+        eprintln!("cannot read `{}`: {error}", args.path);
+        return ExitCode::from(2);
+    }
+    let path = if args.path == "-" {
+        "<stdin>"
+    } else {
+        &args.path
+    };
+
+    let program = match Parser::parse_with(&source, args.opt) {
+        Ok(program) => program,
+        Err(errors) => {
+            errors
+                .iter()
+                .for_each(|error| eprint!("{}", error.render(&source, path)));
+            return ExitCode::from(1);
+        }
+    };
+    for warning in program.warnings() {
+        eprint!("{}", warning.render(&source, path));
+    }
+
+    let emit = match args.emit {
+        None if !args.run => Some(Emit::Asm),
+        emit => emit,
+    };
+    let mut stdout = io::stdout();
+    let written = match emit {
+        Some(Emit::Ast) => stdout.write_all(program.dump_ast().as_bytes()),
+        Some(Emit::Indexed) => stdout.write_all(program.dump_indexed().as_bytes()),
+        Some(Emit::Ir) => stdout.write_all(program.dump_ir().as_bytes()),
+        Some(Emit::Code) => stdout.write_all(program.dump_code().as_bytes()),
+        Some(Emit::Asm) => write!(stdout, "{program}"),
+        Some(Emit::Bytecode) => stdout.write_all(&program.bytecode()),
+        None => Ok(()),
+    };
+    if let Err(error) = written.and_then(|()| stdout.flush()) {
+        eprintln!("cannot write the output: {error}");
+        return ExitCode::from(2);
+    }
+
+    if args.run {
+        let res = match args.inputs {
+            Some(inputs) => {
+                let mut inputs = inputs.into_iter();
+                program.eval_with(|| inputs.next(), |val| println!("{val}"), false)
+            }
+            None => {
+                let mut pending = VecDeque::new();
+                program.eval_with(|| read_input(&mut pending), |val| println!("{val}"), false)
+            }
+        };
+        if let Err(error) = res {
+            eprintln!("{path}:{error}");
+            return ExitCode::from(3);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut path = None;
+    let mut opt = OptLevel::O0;
+    let mut emit = None;
+    let mut run = false;
+    let mut inputs = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O0" => opt = OptLevel::O0,
+            "-O1" => opt = OptLevel::O1,
+            "-O2" => opt = OptLevel::O2,
+            "--run" => run = true,
+            "--emit" => {
+                emit = Some(match args.next().as_deref() {
+                    Some("ast") => Emit::Ast,
+                    Some("indexed") => Emit::Indexed,
+                    Some("ir") => Emit::Ir,
+                    Some("code") => Emit::Code,
+                    Some("asm") => Emit::Asm,
+                    Some("bytecode") => Emit::Bytecode,
+                    Some(kind) => return Err(format!("unknown kind of output `{kind}`")),
+                    None => return Err("`--emit` needs a kind of output".to_string()),
+                })
+            }
+            "--input" => {
+                let list = args.next().ok_or("`--input` needs a list of numbers")?;
+                let numbers = list
+                    .split(',')
+                    .filter(|number| !number.trim().is_empty())
+                    .map(|number| {
+                        number
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("`{}` is not a number", number.trim()))
+                    })
+                    .collect::<Result<_, _>>()?;
+                inputs = Some(numbers);
+            }
+            "-h" | "--help" => return Err(String::new()),
+            arg if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown option `{arg}`"))
+            }
+            arg if path.is_none() => path = Some(arg.to_string()),
+            arg => return Err(format!("more than one file given, `{arg}`")),
+        }
+    }
+    if inputs.is_some() {
+        run = true;
+    }
+    let path = path.ok_or("no file given")?;
+    if path == "-" && run && inputs.is_none() {
+        return Err("the source takes up stdin, `--run` needs `--input`".to_string());
+    }
+    Ok(Args {
+        path,
+        opt,
+        emit,
+        run,
+        inputs,
+    })
+}
+
+/// Next number on stdin, separated by whitespace or commas.
+fn read_input(pending: &mut VecDeque<String>) -> Option<f64> {
+    loop {
+        if let Some(word) = pending.pop_front() {
+            match word.parse() {
+                Ok(val) => return Some(val),
+                Err(_) => {
+                    eprintln!("`{word}` is not a number");
+                    continue;
+                }
+            }
+        }
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        pending.extend(
+            line.split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|word| !word.is_empty())
+                .map(str::to_string),
+        );
+    }
+}
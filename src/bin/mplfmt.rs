@@ -0,0 +1,73 @@
+//! Source formatter, `mplfmt [--check] [FILE...]`.
+
+use std::{
+    io::{self, Read, Write},
+    process::ExitCode,
+};
+
+const USAGE: &str = "\
+usage: mplfmt [--check] [FILE...]
+  FILE     source file to format in place, stdin to stdout when there is none or it is `-`
+  --check  change nothing, fail when a file is not formatted";
+
+fn main() -> ExitCode {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => check = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            arg if arg.starts_with('-') && arg != "-" => {
+                eprintln!("unknown option `{arg}`\n{USAGE}");
+                return ExitCode::from(2);
+            }
+            arg => paths.push(arg.to_string()),
+        }
+    }
+    if paths.is_empty() {
+        paths.push("-".to_string());
+    }
+
+    let mut code = ExitCode::SUCCESS;
+    for path in &paths {
+        let mut source = String::new();
+        let read = match path.as_str() {
+            "-" => io::stdin().read_to_string(&mut source).map(drop),
+            path => std::fs::read_to_string(path).map(|text| source = text),
+        };
+        if let Err(error) = read {
+            eprintln!("cannot read `{path}`: {error}");
+            return ExitCode::from(2);
+        }
+        let name = if path == "-" { "<stdin>" } else { path };
+
+        let formatted = match mpl_sc_lib::format(&source) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprint!("{}", error.render(&source, name));
+                code = ExitCode::from(1);
+                continue;
+            }
+        };
+        if check {
+            if formatted != source {
+                eprintln!("`{name}` is not formatted");
+                code = ExitCode::from(1);
+            }
+            continue;
+        }
+        let written = match path.as_str() {
+            "-" => io::stdout().write_all(formatted.as_bytes()),
+            _ if formatted == source => Ok(()),
+            path => std::fs::write(path, formatted),
+        };
+        if let Err(error) = written {
+            eprintln!("cannot write `{name}`: {error}");
+            return ExitCode::from(2);
+        }
+    }
+    code
+}
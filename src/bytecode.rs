@@ -0,0 +1,46 @@
+use mpl_vm::Instructions;
+
+/// First bytes of every encoded program, the last one is the version of the format.
+const MAGIC: [u8; 4] = *b"MPL\x01";
+
+/// Encodes `code` the way `Parser::bytecode` describes.
+pub(super) fn encode(code: &[Instructions]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    let len = u32::try_from(code.len()).expect("programs are shorter than 2^32 instructions");
+    bytes.extend(len.to_le_bytes());
+    for inst in code {
+        let (opcode, operand) = match inst {
+            Instructions::Psh(val) => (0, val.to_le_bytes().to_vec()),
+            Instructions::Pop => (1, Vec::new()),
+            Instructions::Pek => (2, Vec::new()),
+            Instructions::Inp => (3, Vec::new()),
+            Instructions::Sap(id) => (4, vec![*id]),
+            Instructions::Pfa => (5, Vec::new()),
+            Instructions::Pta => (6, Vec::new()),
+            Instructions::Add => (7, Vec::new()),
+            Instructions::Sub => (8, Vec::new()),
+            Instructions::Mul => (9, Vec::new()),
+            Instructions::Div => (10, Vec::new()),
+            Instructions::Mod => (11, Vec::new()),
+            Instructions::Abs => (12, Vec::new()),
+            Instructions::Max => (13, Vec::new()),
+            Instructions::Min => (14, Vec::new()),
+            Instructions::Eql => (15, Vec::new()),
+            Instructions::Mor => (16, Vec::new()),
+            Instructions::Les => (17, Vec::new()),
+            Instructions::Jmp(target) => (18, index(*target)),
+            Instructions::Jiz(target) => (19, index(*target)),
+            Instructions::Jnz(target) => (20, index(*target)),
+        };
+        bytes.push(opcode);
+        bytes.extend(operand);
+    }
+    bytes
+}
+
+fn index(target: usize) -> Vec<u8> {
+    u32::try_from(target)
+        .expect("programs are shorter than 2^32 instructions")
+        .to_le_bytes()
+        .to_vec()
+}
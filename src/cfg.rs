@@ -0,0 +1,331 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    ops::Range,
+};
+
+use super::{
+    ast_indexed::label,
+    diagnostic::{Diagnostic, Span},
+    ir::{Ir, IrInst},
+};
+
+/// How the code goes on after an instruction, for the passes that follow jumps.
+pub(super) enum Flow<'a> {
+    /// on to the next instruction
+    Next,
+    /// on to the next instruction, and the place a jump to the label lands
+    Label(&'a str),
+    /// always to the label
+    Jump(&'a str),
+    /// to the label or on to the next instruction
+    Branch(&'a str),
+    /// out of the code, like a `return`
+    Exit,
+}
+
+/// Index of every label in `code`.
+pub(super) fn labels<T>(code: &[T], flow: impl Fn(&T) -> Flow<'_>) -> HashMap<&str, usize> {
+    code.iter()
+        .enumerate()
+        .filter_map(|(i, inst)| match flow(inst) {
+            Flow::Label(id) => Some((id, i)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Instructions that can run right after each instruction of `code`, the next one first.
+/// The graph of the passes that work on single instructions instead of blocks.
+pub(super) fn successors<T>(code: &[T], flow: impl Fn(&T) -> Flow<'_>) -> Vec<Vec<usize>> {
+    let labels = labels(code, &flow);
+    code.iter()
+        .enumerate()
+        .map(|(i, inst)| {
+            let next = (i + 1 < code.len()).then_some(i + 1);
+            match flow(inst) {
+                Flow::Next | Flow::Label(_) => next.into_iter().collect(),
+                Flow::Jump(id) => labels.get(id).copied().into_iter().collect(),
+                Flow::Branch(id) => next.into_iter().chain(labels.get(id).copied()).collect(),
+                Flow::Exit => Vec::new(),
+            }
+        })
+        .collect()
+}
+
+impl IrInst {
+    pub(super) fn flow(&self) -> Flow<'_> {
+        match self {
+            IrInst::Label(id) => Flow::Label(id),
+            IrInst::Jmp(id) => Flow::Jump(id),
+            IrInst::Jiz(id) | IrInst::Jnz(id) => Flow::Branch(id),
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// Control-flow graph of a compiled program. Blocks are split at labels and after jumps, and
+/// a conditional jump right after the constant it tests only ever goes one way.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// instructions of the block in `Ir`
+    pub(super) range: Range<usize>,
+    /// the instructions, before addresses and labels are turned into numbers
+    pub code: Vec<String>,
+    /// source lines the code comes from, in order
+    pub spans: Vec<Span>,
+    /// indices of the blocks that can run next
+    pub successors: Vec<usize>,
+    /// indices of the blocks that can run right before
+    pub predecessors: Vec<usize>,
+}
+
+impl From<&Ir> for Cfg {
+    fn from(ir: &Ir) -> Cfg {
+        let mut starts = vec![0, ir.0.len()];
+        for (i, inst) in ir.0.iter().enumerate() {
+            match inst {
+                IrInst::Label(_) if i == 0 || !matches!(ir.0[i - 1], IrInst::Label(_)) => {
+                    starts.push(i)
+                }
+                IrInst::Jmp(_) | IrInst::Jiz(_) | IrInst::Jnz(_) => starts.push(i + 1),
+                _ => (),
+            }
+        }
+        starts.sort();
+        starts.dedup();
+
+        let ranges: Vec<_> = starts.windows(2).map(|pair| pair[0]..pair[1]).collect();
+        let block_of: Vec<usize> = ranges
+            .iter()
+            .enumerate()
+            .flat_map(|(block, range)| range.clone().map(move |_| block))
+            .collect();
+        let labels = labels(&ir.0, IrInst::flow);
+        let next_insts = successors(&ir.0, IrInst::flow);
+
+        let mut line = None;
+        let mut blocks: Vec<_> = ranges
+            .iter()
+            .enumerate()
+            .map(|(block, range)| {
+                let next = (block + 1 < ranges.len()).then_some(block + 1);
+                let test = match range.end.checked_sub(2).map(|i| &ir.0[i]) {
+                    Some(IrInst::Psh(val)) if range.len() > 1 => Some(*val == 0.0),
+                    _ => None,
+                };
+                let last = range.end - 1;
+                let mut successors: Vec<usize> = match (&ir.0[last], test) {
+                    (IrInst::Jiz(id) | IrInst::Jnz(id), Some(zero)) => {
+                        let jiz = matches!(ir.0[last], IrInst::Jiz(_));
+                        if zero == jiz {
+                            labels
+                                .get(id.as_str())
+                                .map(|i| block_of[*i])
+                                .into_iter()
+                                .collect()
+                        } else {
+                            next.into_iter().collect()
+                        }
+                    }
+                    _ => next_insts[last].iter().map(|i| block_of[*i]).collect(),
+                };
+                successors.dedup();
+                let mut spans = Vec::new();
+                let mut code = Vec::new();
+                for inst in &ir.0[range.clone()] {
+                    match inst {
+                        IrInst::Line(span) => line = *span,
+                        inst => {
+                            if line.is_some() && spans.last() != line.as_ref() {
+                                spans.extend(line);
+                            }
+                            code.push(inst.to_string());
+                        }
+                    }
+                }
+                Block {
+                    range: range.clone(),
+                    code,
+                    spans,
+                    successors,
+                    predecessors: Vec::new(),
+                }
+            })
+            .collect();
+        for block in 0..blocks.len() {
+            for succ in blocks[block].successors.clone() {
+                blocks[succ].predecessors.push(block);
+            }
+        }
+        Cfg { blocks }
+    }
+}
+
+impl Cfg {
+    /// The graph in the Graphviz DOT language.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = match (block.spans.first(), block.spans.last()) {
+                (Some(first), Some(last)) if first.start.line == last.start.line => {
+                    format!("line {}\\l", first.start.line)
+                }
+                (Some(first), Some(last)) => {
+                    format!("lines {}-{}\\l", first.start.line, last.start.line)
+                }
+                _ => String::new(),
+            };
+            for inst in &block.code {
+                label += &inst.replace('\\', "\\\\").replace('"', "\\\"");
+                label += "\\l";
+            }
+            _ = writeln!(dot, "    b{i} [label=\"{label}\"];");
+            for succ in &block.successors {
+                _ = writeln!(dot, "    b{i} -> b{succ};");
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /// Blocks that can run, starting from the first one.
+    pub(super) fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack: Vec<usize> = (!self.blocks.is_empty()).then_some(0).into_iter().collect();
+        while let Some(block) = stack.pop() {
+            if !reachable[block] {
+                reachable[block] = true;
+                stack.extend(&self.blocks[block].successors);
+            }
+        }
+        reachable
+    }
+}
+
+impl Ir {
+    /// One warning for every stretch of source lines whose code can never run. Lines that
+    /// only jump around, like the `} else {` after a `return`, are left out, and so is code
+    /// after a label that no line claims, like the implicit `return 0` of a function. The body
+    /// of a function that is never called is left out too, the function gets a warning of its
+    /// own when nothing calls it at all.
+    pub(super) fn unreachable_code(&self) -> Vec<Diagnostic> {
+        let cfg = Cfg::from(self);
+        let mut warnings = Vec::new();
+        let mut line = None;
+        let mut stretch: Option<Span> = None;
+        let mut uncalled: Option<String> = None;
+        let entry = label("fn", "");
+        for (block, reachable) in cfg.blocks.iter().zip(cfg.reachable()) {
+            let first = &self.0[block.range.start];
+            if let Some(end) = &uncalled {
+                if !matches!(first, IrInst::Label(id) if id == end) {
+                    continue;
+                }
+                uncalled = None;
+            }
+            let function = match first {
+                IrInst::Label(id) if !reachable => id.strip_prefix(&entry).map(|name| (id, name)),
+                _ => None,
+            };
+            if reachable || function.is_some() {
+                warnings.extend(
+                    stretch
+                        .take()
+                        .map(|span| Diagnostic::warning(span, "unreachable code")),
+                );
+                line = None;
+                if let Some((id, name)) = function {
+                    let called = self
+                        .0
+                        .iter()
+                        .any(|inst| matches!(inst, IrInst::Jmp(target) if target == id));
+                    let span = self.0[block.range.clone()]
+                        .iter()
+                        .find_map(|inst| match inst {
+                            IrInst::Line(span) => *span,
+                            _ => None,
+                        });
+                    if let (false, Some(span)) = (called, span) {
+                        warnings.push(Diagnostic::warning(
+                            span,
+                            format!("function `{name}` is never called"),
+                        ));
+                    }
+                    uncalled = Some(label("end_fn", name));
+                }
+                continue;
+            }
+            for inst in &self.0[block.range.clone()] {
+                match inst {
+                    IrInst::Line(span) => line = *span,
+                    IrInst::Label(_) => line = None,
+                    IrInst::Jmp(_) | IrInst::Jiz(_) | IrInst::Jnz(_) => (),
+                    _ => {
+                        if let Some(span) = line {
+                            let start = stretch.map_or(span.start, |stretch| stretch.start);
+                            stretch = Some(Span {
+                                start,
+                                end: span.end,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        warnings.extend(stretch.map(|span| Diagnostic::warning(span, "unreachable code")));
+        warnings
+    }
+
+    /// Resolves jumps on constants, then drops blocks that can never run and labels nothing
+    /// jumps to.
+    pub(super) fn remove_dead_code(&mut self) {
+        let mut ir = Vec::new();
+        for inst in std::mem::take(&mut self.0) {
+            let test = match ir.last() {
+                Some(IrInst::Psh(val)) => Some(*val == 0.0),
+                _ => None,
+            };
+            match (inst, test) {
+                (IrInst::Jiz(id), Some(zero)) => {
+                    ir.pop();
+                    if zero {
+                        ir.push(IrInst::Jmp(id));
+                    }
+                }
+                (IrInst::Jnz(id), Some(zero)) => {
+                    ir.pop();
+                    if !zero {
+                        ir.push(IrInst::Jmp(id));
+                    }
+                }
+                (inst, _) => ir.push(inst),
+            }
+        }
+        self.0 = ir;
+
+        let cfg = Cfg::from(&*self);
+        let mut keep = vec![false; self.0.len()];
+        for (block, reachable) in cfg.blocks.iter().zip(cfg.reachable()) {
+            keep[block.range.clone()].fill(reachable);
+        }
+        let mut keep = keep.into_iter();
+        self.0.retain(|_| keep.next().unwrap_or_default());
+
+        let used: HashSet<String> = self
+            .0
+            .iter()
+            .filter_map(|inst| match inst {
+                IrInst::Jmp(id) | IrInst::Jiz(id) | IrInst::Jnz(id) => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+        self.0
+            .retain(|inst| !matches!(inst, IrInst::Label(id) if !used.contains(id)));
+    }
+}
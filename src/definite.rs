@@ -0,0 +1,226 @@
+use std::collections::{BTreeSet, HashSet};
+
+use super::{
+    ast_indexed::AstIndexed,
+    cfg::{successors, Flow},
+    diagnostic::{Diagnostic, Span},
+    Uninitialized,
+};
+
+/// Checks that every variable is assigned on all paths that lead to a read of it. The main
+/// program and every function body are checked on their own, starting with nothing but the
+/// slots in `entry` or the parameters assigned, and calls are treated like any other
+/// expression. With `Uninitialized::Zero` the reads are only warned about and the variables
+/// are set to 0 where their program or function starts. Gives back the slots that are
+/// assigned on all paths to the end of the main program along with the diagnostics, `None`
+/// when no path gets there.
+pub(super) fn check(
+    root: &mut Vec<AstIndexed>,
+    names: &[String],
+    uninitialized: Uninitialized,
+    entry: BTreeSet<usize>,
+) -> (Vec<Diagnostic>, Option<BTreeSet<usize>>) {
+    let mut assigned = HashSet::new();
+    assignments(root, &mut assigned);
+
+    let mut diagnostics = Vec::new();
+    let mut report = |reads: Vec<(usize, Span)>| {
+        let mut slots = BTreeSet::new();
+        for (id, span) in reads {
+            let name = &names[id];
+            // compiler slots are always assigned before use, and the first read of a
+            // variable is enough to point it out
+            if name.starts_with('#') || !slots.insert(id) {
+                continue;
+            }
+            let message = if assigned.contains(&id) {
+                format!("variable `{name}` may be used before it is assigned")
+            } else {
+                format!("uninitialized variable `{name}`")
+            };
+            diagnostics.push(match uninitialized {
+                Uninitialized::Error => Diagnostic::error(span, message),
+                Uninitialized::Zero => Diagnostic::warning(span, message + ", it starts as 0"),
+            });
+        }
+        slots
+    };
+
+    let flat = statements(root);
+    let mut before = definitely_assigned(&flat, entry);
+    let mut exit = before.pop().flatten();
+    let main = report(unassigned_reads(&flat, &before));
+    for inst in root.iter_mut() {
+        if let AstIndexed::Function(_, params, _, _, body) = inst {
+            let entry = params.iter().copied().collect();
+            let flat = statements(body);
+            let slots = report(unassigned_reads(&flat, &definitely_assigned(&flat, entry)));
+            if uninitialized == Uninitialized::Zero {
+                zero(body, &slots);
+            }
+        }
+    }
+    if uninitialized == Uninitialized::Zero {
+        if let Some(exit) = &mut exit {
+            exit.extend(&main);
+        }
+        zero(root, &main);
+    }
+    (diagnostics, exit)
+}
+
+fn zero(body: &mut Vec<AstIndexed>, slots: &BTreeSet<usize>) {
+    let init = slots
+        .iter()
+        .map(|id| AstIndexed::Assign(*id, Box::new(AstIndexed::Value(0.0))));
+    body.splice(0..0, init);
+}
+
+/// Statements of a program or function body in the order they run, without the bodies of
+/// the functions it defines.
+fn statements(body: &[AstIndexed]) -> Vec<&AstIndexed> {
+    let mut flat = Vec::new();
+    for inst in body {
+        match inst {
+            AstIndexed::Root(inner) => flat.extend(statements(inner)),
+            AstIndexed::Function(..) => (),
+            inst => flat.push(inst),
+        }
+    }
+    flat
+}
+
+/// The slots assigned on all paths to each of `statements`, and to the end of them last.
+/// `None` where no path gets to.
+fn definitely_assigned(
+    statements: &[&AstIndexed],
+    entry: BTreeSet<usize>,
+) -> Vec<Option<BTreeSet<usize>>> {
+    let mut successors = successors(statements, |inst| inst.flow());
+    if let Some(last) = statements.last() {
+        if !matches!(last.flow(), Flow::Jump(_) | Flow::Exit) {
+            successors[statements.len() - 1].push(statements.len());
+        }
+    }
+
+    let mut assigned: Vec<Option<BTreeSet<usize>>> = vec![None; statements.len() + 1];
+    assigned[0] = Some(entry);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, inst) in statements.iter().enumerate() {
+            let Some(mut out) = assigned[i].clone() else {
+                continue;
+            };
+            out.extend(defs(inst));
+            for next in &successors[i] {
+                let merged = match &assigned[*next] {
+                    Some(slots) => slots.intersection(&out).copied().collect(),
+                    None => out.clone(),
+                };
+                if assigned[*next].as_ref() != Some(&merged) {
+                    assigned[*next] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+    assigned
+}
+
+/// Every read of a slot that is not in the `assigned` slots of its statement, with the span
+/// of its line.
+fn unassigned_reads(
+    statements: &[&AstIndexed],
+    assigned: &[Option<BTreeSet<usize>>],
+) -> Vec<(usize, Span)> {
+    let mut span = Span::default();
+    let mut unassigned = Vec::new();
+    for (inst, slots) in statements.iter().zip(assigned) {
+        if let AstIndexed::Line(line) = inst {
+            span = *line;
+        }
+        let Some(slots) = slots else {
+            continue;
+        };
+        let mut ids = Vec::new();
+        uses(inst, &mut ids);
+        for id in ids {
+            if !slots.contains(&id) {
+                unassigned.push((id, span));
+            }
+        }
+    }
+    unassigned
+}
+
+impl AstIndexed {
+    fn flow(&self) -> Flow<'_> {
+        match self {
+            AstIndexed::Label(id) => Flow::Label(id),
+            AstIndexed::Goto(id) => Flow::Jump(id),
+            AstIndexed::GotoIf(id, _) | AstIndexed::GotoIfNot(id, _) => Flow::Branch(id),
+            AstIndexed::Return(..) => Flow::Exit,
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// Slots a statement writes to.
+fn defs(inst: &AstIndexed) -> Vec<usize> {
+    match inst {
+        AstIndexed::Assign(id, _) => vec![*id],
+        AstIndexed::Swap(id0, id1) => vec![*id0, *id1],
+        _ => Vec::new(),
+    }
+}
+
+/// Slots read by a statement or expression.
+fn uses(inst: &AstIndexed, ids: &mut Vec<usize>) {
+    match inst {
+        AstIndexed::Indx(id) => ids.push(*id),
+        AstIndexed::Swap(id0, id1) => ids.extend([*id0, *id1]),
+        AstIndexed::Assign(_, inner)
+        | AstIndexed::Abs(inner)
+        | AstIndexed::GotoIf(_, inner)
+        | AstIndexed::GotoIfNot(_, inner)
+        | AstIndexed::Return(_, inner)
+        | AstIndexed::Drop(inner) => uses(inner, ids),
+        AstIndexed::Add(inner1, inner2)
+        | AstIndexed::Sub(inner1, inner2)
+        | AstIndexed::Mul(inner1, inner2)
+        | AstIndexed::Div(inner1, inner2)
+        | AstIndexed::Mod(inner1, inner2)
+        | AstIndexed::Max(inner1, inner2)
+        | AstIndexed::Min(inner1, inner2)
+        | AstIndexed::Eql(inner1, inner2)
+        | AstIndexed::Mor(inner1, inner2)
+        | AstIndexed::Les(inner1, inner2) => {
+            uses(inner1, ids);
+            uses(inner2, ids)
+        }
+        AstIndexed::Root(inner) | AstIndexed::Print(inner) | AstIndexed::Call(_, inner, _) => {
+            inner.iter().for_each(|inst| uses(inst, ids))
+        }
+        AstIndexed::Value(_)
+        | AstIndexed::Input
+        | AstIndexed::Label(_)
+        | AstIndexed::Goto(_)
+        | AstIndexed::Function(..)
+        | AstIndexed::Line(_) => (),
+    }
+}
+
+/// Every slot the program assigns to somewhere, parameters included.
+fn assignments(body: &[AstIndexed], assigned: &mut HashSet<usize>) {
+    for inst in body {
+        match inst {
+            AstIndexed::Root(inner) => assignments(inner, assigned),
+            AstIndexed::Function(_, params, _, _, body) => {
+                assigned.extend(params);
+                assignments(body, assigned)
+            }
+            inst => assigned.extend(defs(inst)),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::{error, fmt};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub(crate) fn error(span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            span,
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn warning(span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            span,
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+
+    /// The diagnostic with the line of `source` it points at underlined, for a terminal.
+    pub fn render(&self, source: &str, path: &str) -> String {
+        let Position { line, column } = self.span.start;
+        let text = source
+            .lines()
+            .nth(line.saturating_sub(1))
+            .unwrap_or_default();
+        let end = match self.span.end {
+            end if end.line == line => end.column,
+            _ => text.chars().count() + 1,
+        };
+        let pad = " ".repeat(line.to_string().len());
+        format!(
+            "{}: {}\n{pad}--> {path}:{}\n{pad} |\n{line} | {text}\n{pad} | {}{}\n",
+            self.severity,
+            self.message,
+            self.span.start,
+            " ".repeat(column.saturating_sub(1)),
+            "^".repeat(end.saturating_sub(column).max(1)),
+        )
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: {}",
+            self.span.start, self.severity, self.message
+        )
+    }
+}
+
+impl error::Error for Diagnostic {}
@@ -0,0 +1,156 @@
+use std::fmt::Write;
+
+use super::{
+    ast::Ast,
+    ast_indexed::AstIndexed,
+    ir::{Ir, IrInst, IrInst2},
+};
+
+/// Writes one node of a tree on its own line, indented two spaces for every level.
+fn node(out: &mut String, depth: usize, label: impl AsRef<str>) {
+    _ = writeln!(out, "{:indent$}{}", "", label.as_ref(), indent = depth * 2);
+}
+
+fn list(items: &[impl ToString]) -> String {
+    let items: Vec<_> = items.iter().map(ToString::to_string).collect();
+    format!("[{}]", items.join(", "))
+}
+
+impl Ast {
+    /// Indented tree of the program, every statement starting with the position of its line.
+    pub(super) fn dump(&self) -> String {
+        let mut out = String::new();
+        self.dump_into(&mut out, 0, "");
+        out
+    }
+
+    fn dump_into(&self, out: &mut String, depth: usize, prefix: &str) {
+        let (label, children): (String, Vec<&Ast>) = match self {
+            Ast::Root(inner) => {
+                node(out, depth, format!("{prefix}root"));
+                for (span, inst) in inner {
+                    inst.dump_into(out, depth + 1, &format!("{} ", span.start));
+                }
+                return;
+            }
+            Ast::Value(val) => (format!("value {val}"), Vec::new()),
+            Ast::Idnt(name) => (format!("idnt {name}"), Vec::new()),
+            Ast::Assign(name, inner) => (format!("assign {name}"), vec![inner]),
+            Ast::Let(name, inner) => (format!("let {name}"), vec![inner]),
+            Ast::Input => ("input".to_string(), Vec::new()),
+            Ast::Print(args) => ("print".to_string(), args.iter().collect()),
+            Ast::Add(inner1, inner2) => ("add".to_string(), vec![inner1, inner2]),
+            Ast::Sub(inner1, inner2) => ("sub".to_string(), vec![inner1, inner2]),
+            Ast::Mul(inner1, inner2) => ("mul".to_string(), vec![inner1, inner2]),
+            Ast::Div(inner1, inner2) => ("div".to_string(), vec![inner1, inner2]),
+            Ast::Mod(inner1, inner2) => ("mod".to_string(), vec![inner1, inner2]),
+            Ast::Abs(inner) => ("abs".to_string(), vec![inner]),
+            Ast::Max(inner1, inner2) => ("max".to_string(), vec![inner1, inner2]),
+            Ast::Min(inner1, inner2) => ("min".to_string(), vec![inner1, inner2]),
+            Ast::Eql(inner1, inner2) => ("eql".to_string(), vec![inner1, inner2]),
+            Ast::Mor(inner1, inner2) => ("mor".to_string(), vec![inner1, inner2]),
+            Ast::Les(inner1, inner2) => ("les".to_string(), vec![inner1, inner2]),
+            Ast::Swap(name1, name2) => (format!("swap {name1} {name2}"), Vec::new()),
+            Ast::Label(name) => (format!("label {name}"), Vec::new()),
+            Ast::Goto(name) => (format!("goto {name}"), Vec::new()),
+            Ast::GotoIf(name, cond) => (format!("goto_if {name}"), vec![cond]),
+            Ast::GotoIfNot(name, cond) => (format!("goto_if_not {name}"), vec![cond]),
+            Ast::While(cond) => ("while".to_string(), vec![cond]),
+            Ast::WhileNot(cond) => ("while_not".to_string(), vec![cond]),
+            Ast::If(cond) => ("if".to_string(), vec![cond]),
+            Ast::IfNot(cond) => ("if_not".to_string(), vec![cond]),
+            Ast::ElseIf(cond) => ("else_if".to_string(), vec![cond]),
+            Ast::ElseIfNot(cond) => ("else_if_not".to_string(), vec![cond]),
+            Ast::Else => ("else".to_string(), Vec::new()),
+            Ast::End => ("end".to_string(), Vec::new()),
+            Ast::Fn(name, params) => (format!("fn {name} {}", list(params)), Vec::new()),
+            Ast::Return(inner) => (
+                "return".to_string(),
+                inner.iter().map(|inner| &**inner).collect(),
+            ),
+            Ast::Call(name, args) => (format!("call {name}"), args.iter().collect()),
+            Ast::Comment(text) => (format!("comment {text:?}"), Vec::new()),
+        };
+        node(out, depth, format!("{prefix}{label}"));
+        for child in children {
+            child.dump_into(out, depth + 1, "");
+        }
+    }
+}
+
+impl AstIndexed {
+    /// Indented tree of the program, with slots in place of variables.
+    pub(super) fn dump(&self) -> String {
+        let mut out = String::new();
+        self.dump_into(&mut out, 0);
+        out
+    }
+
+    fn dump_into(&self, out: &mut String, depth: usize) {
+        let (label, children): (String, Vec<&AstIndexed>) = match self {
+            AstIndexed::Root(inner) => ("root".to_string(), inner.iter().collect()),
+            AstIndexed::Value(val) => (format!("value {val}"), Vec::new()),
+            AstIndexed::Indx(id) => (format!("indx {id}"), Vec::new()),
+            AstIndexed::Assign(id, inner) => (format!("assign {id}"), vec![inner]),
+            AstIndexed::Input => ("input".to_string(), Vec::new()),
+            AstIndexed::Print(args) => ("print".to_string(), args.iter().collect()),
+            AstIndexed::Add(inner1, inner2) => ("add".to_string(), vec![inner1, inner2]),
+            AstIndexed::Sub(inner1, inner2) => ("sub".to_string(), vec![inner1, inner2]),
+            AstIndexed::Mul(inner1, inner2) => ("mul".to_string(), vec![inner1, inner2]),
+            AstIndexed::Div(inner1, inner2) => ("div".to_string(), vec![inner1, inner2]),
+            AstIndexed::Mod(inner1, inner2) => ("mod".to_string(), vec![inner1, inner2]),
+            AstIndexed::Abs(inner) => ("abs".to_string(), vec![inner]),
+            AstIndexed::Max(inner1, inner2) => ("max".to_string(), vec![inner1, inner2]),
+            AstIndexed::Min(inner1, inner2) => ("min".to_string(), vec![inner1, inner2]),
+            AstIndexed::Eql(inner1, inner2) => ("eql".to_string(), vec![inner1, inner2]),
+            AstIndexed::Mor(inner1, inner2) => ("mor".to_string(), vec![inner1, inner2]),
+            AstIndexed::Les(inner1, inner2) => ("les".to_string(), vec![inner1, inner2]),
+            AstIndexed::Swap(id0, id1) => (format!("swap {id0} {id1}"), Vec::new()),
+            AstIndexed::Label(name) => (format!("label {name}"), Vec::new()),
+            AstIndexed::Goto(name) => (format!("goto {name}"), Vec::new()),
+            AstIndexed::GotoIf(name, cond) => (format!("goto_if {name}"), vec![cond]),
+            AstIndexed::GotoIfNot(name, cond) => (format!("goto_if_not {name}"), vec![cond]),
+            AstIndexed::Function(name, params, ra, frame, body) => (
+                format!(
+                    "function {name} params {} ra {ra} frame {}",
+                    list(params),
+                    list(frame)
+                ),
+                body.iter().collect(),
+            ),
+            AstIndexed::Call(name, args, result) => (
+                format!("call {name} result {result}"),
+                args.iter().collect(),
+            ),
+            AstIndexed::Return(name, inner) => (format!("return {name}"), vec![inner]),
+            AstIndexed::Drop(inner) => ("drop".to_string(), vec![inner]),
+            AstIndexed::Line(span) => (format!("line {}", span.start), Vec::new()),
+        };
+        node(out, depth, label);
+        for child in children {
+            child.dump_into(out, depth + 1);
+        }
+    }
+}
+
+/// Listing with labels at the start of their line and everything else indented under them.
+fn listing<T: ToString>(code: &[T], is_label: impl Fn(&T) -> bool) -> String {
+    let mut out = String::new();
+    for inst in code {
+        let indent = if is_label(inst) { 0 } else { 4 };
+        _ = writeln!(out, "{:indent$}{}", "", inst.to_string());
+    }
+    out
+}
+
+impl Ir {
+    /// Listing of the intermediate code, with the memory addresses from `Ir::allocate`.
+    pub(super) fn dump(&self) -> String {
+        listing(&self.0, |inst| matches!(inst, IrInst::Label(_)))
+    }
+}
+
+/// Listing of the code for `mpl_vm` after the peephole pass, before labels are resolved.
+pub(super) fn dump_code(prog: &[IrInst2]) -> String {
+    listing(prog, |inst| matches!(inst, IrInst2::Label(_)))
+}
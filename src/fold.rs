@@ -0,0 +1,113 @@
+use super::ast_indexed::AstIndexed;
+
+impl AstIndexed {
+    /// Computes operations on constants at compile time and drops operations that give
+    /// back their operand unchanged for every `f64`. Division by zero is left for the VM.
+    pub(super) fn fold(self) -> AstIndexed {
+        match self {
+            AstIndexed::Root(inner) => {
+                AstIndexed::Root(inner.into_iter().map(AstIndexed::fold).collect())
+            }
+            AstIndexed::Assign(id, inner) => AstIndexed::Assign(id, Box::new(inner.fold())),
+            AstIndexed::Print(inner) => {
+                AstIndexed::Print(inner.into_iter().map(AstIndexed::fold).collect())
+            }
+            AstIndexed::Add(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(val1 + val2)
+                }
+                // `x + 0` is not `x` when `x` is `-0`, `x + -0` always is
+                (inner, AstIndexed::Value(zero)) | (AstIndexed::Value(zero), inner)
+                    if zero == 0.0 && zero.is_sign_negative() =>
+                {
+                    inner
+                }
+                (inner1, inner2) => AstIndexed::Add(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Sub(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(val1 - val2)
+                }
+                (inner, AstIndexed::Value(zero)) if zero == 0.0 && zero.is_sign_positive() => inner,
+                (inner1, inner2) => AstIndexed::Sub(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Mul(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(val1 * val2)
+                }
+                (inner, AstIndexed::Value(1.0)) | (AstIndexed::Value(1.0), inner) => inner,
+                (inner1, inner2) => AstIndexed::Mul(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Div(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) if val2 != 0.0 => {
+                    AstIndexed::Value(val1 / val2)
+                }
+                (inner, AstIndexed::Value(1.0)) => inner,
+                (inner1, inner2) => AstIndexed::Div(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Mod(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) if val2 != 0.0 => {
+                    AstIndexed::Value(val1 % val2)
+                }
+                (inner1, inner2) => AstIndexed::Mod(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Abs(inner) => match inner.fold() {
+                AstIndexed::Value(val) => AstIndexed::Value(val.abs()),
+                inner => AstIndexed::Abs(Box::new(inner)),
+            },
+            AstIndexed::Max(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(val1.max(val2))
+                }
+                (inner1, inner2) => AstIndexed::Max(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Min(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(val1.min(val2))
+                }
+                (inner1, inner2) => AstIndexed::Min(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Eql(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(f64::from(u8::from(val1 == val2)))
+                }
+                (inner1, inner2) => AstIndexed::Eql(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Mor(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(f64::from(u8::from(val1 > val2)))
+                }
+                (inner1, inner2) => AstIndexed::Mor(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::Les(inner1, inner2) => match (inner1.fold(), inner2.fold()) {
+                (AstIndexed::Value(val1), AstIndexed::Value(val2)) => {
+                    AstIndexed::Value(f64::from(u8::from(val1 < val2)))
+                }
+                (inner1, inner2) => AstIndexed::Les(Box::new(inner1), Box::new(inner2)),
+            },
+            AstIndexed::GotoIf(id, inner) => AstIndexed::GotoIf(id, Box::new(inner.fold())),
+            AstIndexed::GotoIfNot(id, inner) => AstIndexed::GotoIfNot(id, Box::new(inner.fold())),
+            AstIndexed::Function(name, params, ra, frame, body) => AstIndexed::Function(
+                name,
+                params,
+                ra,
+                frame,
+                body.into_iter().map(AstIndexed::fold).collect(),
+            ),
+            AstIndexed::Call(name, args, result) => AstIndexed::Call(
+                name,
+                args.into_iter().map(AstIndexed::fold).collect(),
+                result,
+            ),
+            AstIndexed::Return(name, inner) => AstIndexed::Return(name, Box::new(inner.fold())),
+            AstIndexed::Drop(inner) => AstIndexed::Drop(Box::new(inner.fold())),
+            ai @ (AstIndexed::Value(_)
+            | AstIndexed::Indx(_)
+            | AstIndexed::Input
+            | AstIndexed::Swap(..)
+            | AstIndexed::Label(_)
+            | AstIndexed::Goto(_)
+            | AstIndexed::Line(_)) => ai,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use super::ast::Ast;
+
+/// Spaces in front of a statement for every block it is in.
+const INDENT: usize = 4;
+
+/// Loosest operator first, the same levels as `OPERATORS` in the parser.
+fn precedence(ast: &Ast) -> Option<u8> {
+    match ast {
+        Ast::Eql(..) | Ast::Mor(..) | Ast::Les(..) => Some(0),
+        Ast::Add(..) | Ast::Sub(..) => Some(1),
+        // `-x` binds tighter than any operator
+        Ast::Mul(lhs, rhs) if is_negation(lhs, rhs) => None,
+        Ast::Mul(..) | Ast::Div(..) | Ast::Mod(..) => Some(2),
+        _ => None,
+    }
+}
+
+/// Whether `lhs * rhs` is what the parser makes of `-rhs`, which only folds into the number
+/// when `rhs` is one.
+fn is_negation(lhs: &Ast, rhs: &Ast) -> bool {
+    matches!(lhs, Ast::Value(v) if *v == -1.0) && !matches!(rhs, Ast::Value(_))
+}
+
+/// The shortest text that reads back as `val`, in exponent notation when that is shorter.
+fn number(val: f64) -> String {
+    if val.is_infinite() {
+        return if val > 0.0 { "1e999" } else { "-1e999" }.to_string();
+    }
+    let (plain, exponent) = (val.to_string(), format!("{val:e}"));
+    if exponent.len() < plain.len() {
+        exponent
+    } else {
+        plain
+    }
+}
+
+fn list(items: &[Ast]) -> String {
+    let items: Vec<_> = items.iter().map(Ast::expression).collect();
+    items.join(", ")
+}
+
+impl Ast {
+    /// The program as canonical source, every block indented and every operator surrounded
+    /// by single spaces. Comments stay as they are on the line they were on, runs of empty
+    /// lines shrink to one.
+    pub(super) fn format(&self) -> String {
+        let Ast::Root(inner) = self else {
+            return format!("{}\n", self.statement());
+        };
+        let mut lines: Vec<String> = Vec::new();
+        let mut depth = 0;
+        // lines of the source the last line of output came from
+        let mut last: Option<(usize, usize)> = None;
+        // whether the last line of output holds only comments
+        let mut comments = false;
+        for (span, inst) in inner {
+            let (start, end) = (span.start.line, span.end.line);
+            let on_last = matches!(last, Some((first, last)) if start <= last && end >= first);
+            if let (Ast::Comment(text), Some((first, last)), Some(line), true) =
+                (inst, &mut last, lines.last_mut(), on_last)
+            {
+                *line += " ";
+                *line += text;
+                *first = (*first).min(start);
+                *last = (*last).max(end);
+                continue;
+            }
+            // comments in front of the statement on its line
+            let before = match lines.pop() {
+                Some(line) if comments && on_last => format!("{} ", line.trim_start()),
+                line => {
+                    lines.extend(line);
+                    String::new()
+                }
+            };
+            if matches!(last, Some((_, last)) if start > last + 1) {
+                lines.push(String::new());
+            }
+            if matches!(
+                inst,
+                Ast::End | Ast::Else | Ast::ElseIf(_) | Ast::ElseIfNot(_)
+            ) {
+                depth = usize::saturating_sub(depth, 1);
+            }
+            lines.push(format!(
+                "{:indent$}{before}{}",
+                "",
+                inst.statement(),
+                indent = depth * INDENT
+            ));
+            comments = matches!(inst, Ast::Comment(_));
+            if matches!(
+                inst,
+                Ast::While(_)
+                    | Ast::WhileNot(_)
+                    | Ast::If(_)
+                    | Ast::IfNot(_)
+                    | Ast::ElseIf(_)
+                    | Ast::ElseIfNot(_)
+                    | Ast::Else
+                    | Ast::Fn(..)
+            ) {
+                depth += 1;
+            }
+            last = Some((start, end));
+        }
+        lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+
+    /// One statement without its indentation, `x op= e` wherever it means the same as
+    /// `x = x op e`.
+    fn statement(&self) -> String {
+        match self {
+            Ast::Assign(name, inner) => {
+                let (op, lhs, rhs) = match &**inner {
+                    Ast::Add(lhs, rhs) => ("+=", lhs, rhs),
+                    Ast::Sub(lhs, rhs) => ("-=", lhs, rhs),
+                    Ast::Mul(lhs, rhs) => ("*=", lhs, rhs),
+                    Ast::Div(lhs, rhs) => ("/=", lhs, rhs),
+                    Ast::Mod(lhs, rhs) => ("%=", lhs, rhs),
+                    inner => return format!("{name} = {}", inner.expression()),
+                };
+                match &**lhs {
+                    Ast::Idnt(var) if var == name => format!("{name} {op} {}", rhs.expression()),
+                    _ => format!("{name} = {}", inner.expression()),
+                }
+            }
+            Ast::Let(name, inner) => format!("let {name} = {}", inner.expression()),
+            Ast::Swap(name1, name2) => format!("swap {name1} and {name2}"),
+            Ast::Label(name) => format!("{name}:"),
+            Ast::Goto(name) => format!("goto {name}"),
+            Ast::GotoIf(name, cond) => format!("goto {name} if {} = 0", cond.operand(0, false)),
+            Ast::GotoIfNot(name, cond) => format!("goto {name} if {} != 0", cond.expression()),
+            Ast::While(cond) => format!("while not {} {{", cond.expression()),
+            Ast::WhileNot(cond) => format!("while {} {{", cond.expression()),
+            Ast::If(cond) => format!("if not {} {{", cond.expression()),
+            Ast::IfNot(cond) => format!("if {} {{", cond.expression()),
+            Ast::ElseIf(cond) => format!("}} else if not {} {{", cond.expression()),
+            Ast::ElseIfNot(cond) => format!("}} else if {} {{", cond.expression()),
+            Ast::Else => "} else {".to_string(),
+            Ast::End => "}".to_string(),
+            Ast::Fn(name, params) => format!("fn {name}({}) {{", params.join(", ")),
+            Ast::Return(Some(inner)) => format!("return {}", inner.expression()),
+            Ast::Return(None) => "return".to_string(),
+            Ast::Comment(text) => text.clone(),
+            Ast::Root(_) => self.format(),
+            inner => inner.expression(),
+        }
+    }
+
+    /// An expression with as few parentheses as keep it the same tree.
+    fn expression(&self) -> String {
+        let (op, lhs, rhs) = match self {
+            Ast::Value(val) => return number(*val),
+            Ast::Idnt(name) => return name.clone(),
+            Ast::Input => return "input()".to_string(),
+            Ast::Print(args) => return format!("print({})", list(args)),
+            Ast::Abs(inner) => return format!("abs({})", inner.expression()),
+            Ast::Max(inner1, inner2) => {
+                return format!("max({}, {})", inner1.expression(), inner2.expression())
+            }
+            Ast::Min(inner1, inner2) => {
+                return format!("min({}, {})", inner1.expression(), inner2.expression())
+            }
+            Ast::Call(name, args) => return format!("{name}({})", list(args)),
+            Ast::Mul(lhs, rhs) if is_negation(lhs, rhs) => {
+                return match &**rhs {
+                    // `--a` would read like a typo
+                    Ast::Mul(inner_lhs, inner_rhs) if is_negation(inner_lhs, inner_rhs) => {
+                        format!("-({})", rhs.expression())
+                    }
+                    _ => format!("-{}", rhs.operand(u8::MAX, true)),
+                }
+            }
+            Ast::Add(lhs, rhs) => ("+", lhs, rhs),
+            Ast::Sub(lhs, rhs) => ("-", lhs, rhs),
+            Ast::Mul(lhs, rhs) => ("*", lhs, rhs),
+            Ast::Div(lhs, rhs) => ("/", lhs, rhs),
+            Ast::Mod(lhs, rhs) => ("%", lhs, rhs),
+            Ast::Eql(lhs, rhs) => ("=", lhs, rhs),
+            Ast::Mor(lhs, rhs) => (">", lhs, rhs),
+            Ast::Les(lhs, rhs) => ("<", lhs, rhs),
+            inner => return inner.statement(),
+        };
+        let prec = precedence(self).unwrap_or_default();
+        format!(
+            "{} {op} {}",
+            lhs.operand(prec, false),
+            rhs.operand(prec, true)
+        )
+    }
+
+    /// An operand of an operator of precedence `prec`, in parentheses when it would come
+    /// apart otherwise. Every operator is left associative, so the right operand needs them
+    /// for an operator of the same precedence as well.
+    fn operand(&self, prec: u8, right: bool) -> String {
+        match precedence(self) {
+            Some(inner) if inner < prec || (right && inner == prec) => {
+                format!("({})", self.expression())
+            }
+            _ => self.expression(),
+        }
+    }
+}
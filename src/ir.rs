@@ -1,13 +1,23 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 
-use super::ast_indexed::AstIndexed;
+use super::{
+    ast_indexed::{label, AstIndexed},
+    diagnostic::Span,
+    peephole,
+    source_map::SourceMap,
+};
 use mpl_vm::Instructions;
 
-enum IrInst {
+/// `mpl_vm` selects a memory cell with the `u8` operand of a single `Sap`, so this is as much
+/// memory as a program can use. Everything before `Ir::allocate` works with wider addresses.
+pub(super) const MEMORY_SIZE: usize = u8::MAX as usize + 1;
+
+pub(super) enum IrInst {
     Psh(f64),
-    Pfa(u8),
-    Pta(u8),
+    Pfa(usize),
+    Pta(usize),
     Pek,
+    Pop,
     Inp,
     Add,
     Sub,
@@ -20,16 +30,20 @@ enum IrInst {
     Eql,
     Mor,
     Les,
-    Swap(u8, u8),
+    Swap(usize, usize),
     Label(String),
     Jmp(String),
     Jiz(String),
     Jnz(String),
+    /// statement the code after it comes from, `None` for code the compiler adds
+    Line(Option<Span>),
+    /// where the code that hands the variables of a `Session` back starts
+    Epilogue,
 }
 
-enum IrInst2 {
+pub(super) enum IrInst2 {
     Psh(f64),
-    Sap(u8),
+    Sap(usize),
     Pfa,
     Pta,
     Pek,
@@ -49,102 +63,262 @@ enum IrInst2 {
     Jmp(String),
     Jiz(String),
     Jnz(String),
+    Label(String),
+    Line(Option<Span>),
+    Epilogue,
 }
 
-pub(super) struct Ir(Vec<IrInst>);
+pub(super) struct Ir(pub(super) Vec<IrInst>);
+
+/// Functions are called by saving the callee's frame on the stack, passing the arguments
+/// through the parameter slots and the number of the call site through the return address
+/// slot. `return` jumps to a dispatcher which compares the return address with every call
+/// site of the function, and the caller restores the frame once it is back.
+struct Calls<'a> {
+    functions: HashMap<&'a str, (&'a [usize], usize, &'a [usize])>,
+    sites: HashMap<&'a str, Vec<usize>>,
+    counter: usize,
+}
 
 impl From<AstIndexed> for Ir {
     fn from(ai: AstIndexed) -> Ir {
         let mut ir = Vec::new();
-        IrInst::update(&ai, &mut ir);
+        let mut calls = Calls::from(&ai);
+        IrInst::update(&ai, &mut ir, &mut calls);
+        calls.dispatch(&mut ir);
         Ir(ir)
     }
 }
 
+impl<'a> From<&'a AstIndexed> for Calls<'a> {
+    fn from(ai: &'a AstIndexed) -> Calls<'a> {
+        let mut functions = HashMap::new();
+        if let AstIndexed::Root(inner) = ai {
+            for inst in inner {
+                if let AstIndexed::Function(name, params, ra, frame, _) = inst {
+                    functions.insert(name.as_str(), (params.as_slice(), *ra, frame.as_slice()));
+                }
+            }
+        }
+        Calls {
+            functions,
+            sites: HashMap::new(),
+            counter: 0,
+        }
+    }
+}
+
+impl Calls<'_> {
+    fn dispatch(&self, ir: &mut Vec<IrInst>) {
+        if self.functions.is_empty() {
+            return;
+        }
+        ir.push(IrInst::Line(None));
+        ir.push(IrInst::Jmp(label("end", "program")));
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort();
+        for (name, (_, ra, _)) in functions {
+            ir.push(IrInst::Label(label("return", name)));
+            let sites = self.sites.get(name).map(Vec::as_slice).unwrap_or_default();
+            if let Some((last, sites)) = sites.split_last() {
+                for site in sites {
+                    ir.push(IrInst::Pfa(*ra));
+                    ir.push(IrInst::Psh(*site as f64));
+                    ir.push(IrInst::Eql);
+                    ir.push(IrInst::Jnz(label("call", site)));
+                }
+                ir.push(IrInst::Jmp(label("call", last)));
+            }
+        }
+        ir.push(IrInst::Label(label("end", "program")));
+    }
+}
+
+impl fmt::Display for IrInst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrInst::Psh(val) => write!(f, "psh {val}"),
+            IrInst::Pfa(id) => write!(f, "pfa {id}"),
+            IrInst::Pta(id) => write!(f, "pta {id}"),
+            IrInst::Pek => write!(f, "pek"),
+            IrInst::Pop => write!(f, "pop"),
+            IrInst::Inp => write!(f, "inp"),
+            IrInst::Add => write!(f, "add"),
+            IrInst::Sub => write!(f, "sub"),
+            IrInst::Mul => write!(f, "mul"),
+            IrInst::Div => write!(f, "div"),
+            IrInst::Mod => write!(f, "mod"),
+            IrInst::Abs => write!(f, "abs"),
+            IrInst::Max => write!(f, "max"),
+            IrInst::Min => write!(f, "min"),
+            IrInst::Eql => write!(f, "eql"),
+            IrInst::Mor => write!(f, "mor"),
+            IrInst::Les => write!(f, "les"),
+            IrInst::Swap(id0, id1) => write!(f, "swap {id0} {id1}"),
+            IrInst::Label(id) => write!(f, "{id}:"),
+            IrInst::Jmp(id) => write!(f, "jmp {id}"),
+            IrInst::Jiz(id) => write!(f, "jiz {id}"),
+            IrInst::Jnz(id) => write!(f, "jnz {id}"),
+            IrInst::Line(Some(span)) => write!(f, "# line {}", span.start.line),
+            IrInst::Line(None) => write!(f, "# added"),
+            IrInst::Epilogue => write!(f, "# epilogue"),
+        }
+    }
+}
+
+impl fmt::Display for IrInst2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrInst2::Psh(val) => write!(f, "psh {val}"),
+            IrInst2::Sap(id) => write!(f, "sap {id}"),
+            IrInst2::Pfa => write!(f, "pfa"),
+            IrInst2::Pta => write!(f, "pta"),
+            IrInst2::Pek => write!(f, "pek"),
+            IrInst2::Pop => write!(f, "pop"),
+            IrInst2::Inp => write!(f, "inp"),
+            IrInst2::Add => write!(f, "add"),
+            IrInst2::Sub => write!(f, "sub"),
+            IrInst2::Mul => write!(f, "mul"),
+            IrInst2::Div => write!(f, "div"),
+            IrInst2::Mod => write!(f, "mod"),
+            IrInst2::Abs => write!(f, "abs"),
+            IrInst2::Max => write!(f, "max"),
+            IrInst2::Min => write!(f, "min"),
+            IrInst2::Eql => write!(f, "eql"),
+            IrInst2::Mor => write!(f, "mor"),
+            IrInst2::Les => write!(f, "les"),
+            IrInst2::Jmp(id) => write!(f, "jmp {id}"),
+            IrInst2::Jiz(id) => write!(f, "jiz {id}"),
+            IrInst2::Jnz(id) => write!(f, "jnz {id}"),
+            IrInst2::Label(id) => write!(f, "{id}:"),
+            IrInst2::Line(Some(span)) => write!(f, "# line {}", span.start.line),
+            IrInst2::Line(None) => write!(f, "# added"),
+            IrInst2::Epilogue => write!(f, "# epilogue"),
+        }
+    }
+}
+
 impl IrInst {
-    fn update(ai: &AstIndexed, ir: &mut Vec<IrInst>) {
+    fn update<'a>(ai: &'a AstIndexed, ir: &mut Vec<IrInst>, calls: &mut Calls<'a>) {
         match ai {
-            AstIndexed::Root(inner) => inner.iter().for_each(|inst| IrInst::update(inst, ir)),
+            AstIndexed::Root(inner) => inner
+                .iter()
+                .for_each(|inst| IrInst::update(inst, ir, calls)),
             AstIndexed::Value(val) => ir.push(IrInst::Psh(*val)),
             AstIndexed::Indx(id) => ir.push(IrInst::Pfa(*id)),
             AstIndexed::Assign(id, inner) => {
-                IrInst::update(inner, ir);
+                IrInst::update(inner, ir, calls);
                 ir.push(IrInst::Pta(*id))
             }
             AstIndexed::Input => ir.push(IrInst::Inp),
             AstIndexed::Print(inner) => inner.iter().for_each(|inst| {
-                IrInst::update(inst, ir);
+                IrInst::update(inst, ir, calls);
                 ir.push(IrInst::Pek)
             }),
             AstIndexed::Add(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Add)
             }
             AstIndexed::Sub(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Sub)
             }
             AstIndexed::Mul(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Mul)
             }
             AstIndexed::Div(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Div)
             }
             AstIndexed::Mod(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Mod)
             }
             AstIndexed::Abs(inner) => {
-                IrInst::update(inner, ir);
+                IrInst::update(inner, ir, calls);
                 ir.push(IrInst::Abs)
             }
             AstIndexed::Max(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Max)
             }
             AstIndexed::Min(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Min)
             }
             AstIndexed::Eql(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Eql)
             }
             AstIndexed::Mor(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Mor)
             }
             AstIndexed::Les(inner1, inner2) => {
-                IrInst::update(inner1, ir);
-                IrInst::update(inner2, ir);
+                IrInst::update(inner1, ir, calls);
+                IrInst::update(inner2, ir, calls);
                 ir.push(IrInst::Les)
             }
             AstIndexed::Swap(id0, id1) => ir.push(IrInst::Swap(*id0, *id1)),
             AstIndexed::Label(id) => ir.push(IrInst::Label(id.clone())),
             AstIndexed::Goto(id) => ir.push(IrInst::Jmp(id.clone())),
             AstIndexed::GotoIf(id, inner) => {
-                IrInst::update(inner, ir);
+                IrInst::update(inner, ir, calls);
                 ir.push(IrInst::Jiz(id.clone()))
             }
             AstIndexed::GotoIfNot(id, inner) => {
-                IrInst::update(inner, ir);
+                IrInst::update(inner, ir, calls);
                 ir.push(IrInst::Jnz(id.clone()))
             }
+            AstIndexed::Function(name, _, _, _, body) => {
+                ir.push(IrInst::Line(None));
+                ir.push(IrInst::Jmp(label("end_fn", name)));
+                ir.push(IrInst::Label(label("fn", name)));
+                body.iter().for_each(|inst| IrInst::update(inst, ir, calls));
+                ir.push(IrInst::Line(None));
+                ir.push(IrInst::Psh(0.0));
+                ir.push(IrInst::Jmp(label("return", name)));
+                ir.push(IrInst::Label(label("end_fn", name)))
+            }
+            AstIndexed::Return(name, inner) => {
+                IrInst::update(inner, ir, calls);
+                ir.push(IrInst::Jmp(label("return", name)))
+            }
+            AstIndexed::Call(name, args, result) => {
+                let (params, ra, frame) = calls.functions[name.as_str()];
+                let site = calls.counter;
+                calls.counter += 1;
+                calls.sites.entry(name).or_default().push(site);
+                frame.iter().for_each(|id| ir.push(IrInst::Pfa(*id)));
+                args.iter().for_each(|arg| IrInst::update(arg, ir, calls));
+                params.iter().rev().for_each(|id| ir.push(IrInst::Pta(*id)));
+                ir.push(IrInst::Psh(site as f64));
+                ir.push(IrInst::Pta(ra));
+                ir.push(IrInst::Jmp(label("fn", name)));
+                ir.push(IrInst::Label(label("call", site)));
+                ir.push(IrInst::Pta(*result));
+                frame.iter().rev().for_each(|id| ir.push(IrInst::Pta(*id)));
+                ir.push(IrInst::Pfa(*result))
+            }
+            AstIndexed::Drop(inner) => {
+                IrInst::update(inner, ir, calls);
+                ir.push(IrInst::Pop)
+            }
+            AstIndexed::Line(span) => ir.push(IrInst::Line(Some(*span))),
         }
     }
 
-    fn codegen(&self, prog: &mut Vec<IrInst2>, lblmgr: &mut HashMap<String, usize>) {
+    fn codegen(&self, prog: &mut Vec<IrInst2>) {
         match self {
             IrInst::Psh(val) => prog.push(IrInst2::Psh(*val)),
             IrInst::Pfa(id) => {
@@ -160,6 +334,7 @@ impl IrInst {
                 prog.push(IrInst2::Pek);
                 prog.push(IrInst2::Pop)
             }
+            IrInst::Pop => prog.push(IrInst2::Pop),
             IrInst::Add => prog.push(IrInst2::Add),
             IrInst::Sub => prog.push(IrInst2::Sub),
             IrInst::Mul => prog.push(IrInst2::Mul),
@@ -184,42 +359,87 @@ impl IrInst {
             IrInst::Jmp(id) => prog.push(IrInst2::Jmp(id.clone())),
             IrInst::Jiz(id) => prog.push(IrInst2::Jiz(id.clone())),
             IrInst::Jnz(id) => prog.push(IrInst2::Jnz(id.clone())),
-            IrInst::Label(id) => _ = lblmgr.insert(id.clone(), prog.len()),
+            IrInst::Label(id) => prog.push(IrInst2::Label(id.clone())),
+            IrInst::Line(span) => prog.push(IrInst2::Line(*span)),
+            IrInst::Epilogue => prog.push(IrInst2::Epilogue),
         }
     }
 }
 
 impl Ir {
-    pub(super) fn codegen(&self) -> Vec<Instructions> {
-        let mut lblmgr = HashMap::new();
+    /// The code for `mpl_vm` before its labels are resolved.
+    pub(super) fn code(&self, optimise: bool) -> Vec<IrInst2> {
         let mut prog = Vec::new();
         for inst in &self.0 {
-            inst.codegen(&mut prog, &mut lblmgr);
+            inst.codegen(&mut prog);
+        }
+        if optimise {
+            peephole::optimise(&mut prog);
+        }
+        prog
+    }
+
+    /// The program for `mpl_vm`, with the statement every instruction comes from and the
+    /// index of the epilogue, if there is one.
+    pub(super) fn codegen(&self, optimise: bool) -> (Vec<Instructions>, SourceMap, Option<usize>) {
+        let prog = self.code(optimise);
+        let mut lblmgr = HashMap::new();
+        let mut len = 0;
+        let mut epilogue = None;
+        for inst in &prog {
+            match inst {
+                IrInst2::Label(id) => {
+                    let duplicate = lblmgr.insert(id, len).is_some();
+                    debug_assert!(!duplicate, "label `{id}` is defined more than once");
+                }
+                IrInst2::Line(_) => (),
+                IrInst2::Epilogue => epilogue = Some(len),
+                _ => len += 1,
+            }
         }
-        prog.iter()
-            .map(|inst| match inst {
-                IrInst2::Psh(val) => Instructions::Psh(*val),
-                IrInst2::Sap(id) => Instructions::Sap(*id),
-                IrInst2::Pfa => Instructions::Pfa,
-                IrInst2::Pta => Instructions::Pta,
-                IrInst2::Pek => Instructions::Pek,
-                IrInst2::Pop => Instructions::Pop,
-                IrInst2::Inp => Instructions::Inp,
-                IrInst2::Add => Instructions::Add,
-                IrInst2::Sub => Instructions::Sub,
-                IrInst2::Mul => Instructions::Mul,
-                IrInst2::Div => Instructions::Div,
-                IrInst2::Mod => Instructions::Mod,
-                IrInst2::Abs => Instructions::Abs,
-                IrInst2::Max => Instructions::Max,
-                IrInst2::Min => Instructions::Min,
-                IrInst2::Eql => Instructions::Eql,
-                IrInst2::Mor => Instructions::Mor,
-                IrInst2::Les => Instructions::Les,
-                IrInst2::Jmp(id) => Instructions::Jmp(*lblmgr.get(id).unwrap()),
-                IrInst2::Jiz(id) => Instructions::Jiz(*lblmgr.get(id).unwrap()),
-                IrInst2::Jnz(id) => Instructions::Jnz(*lblmgr.get(id).unwrap()),
+        let mut line = None;
+        let mut spans = Vec::new();
+        let code = prog
+            .iter()
+            .filter_map(|inst| {
+                let inst = match inst {
+                    IrInst2::Psh(val) => Instructions::Psh(*val),
+                    IrInst2::Sap(id) => Instructions::Sap(
+                        u8::try_from(*id).expect("addresses are checked by Ir::allocate"),
+                    ),
+                    IrInst2::Pfa => Instructions::Pfa,
+                    IrInst2::Pta => Instructions::Pta,
+                    IrInst2::Pek => Instructions::Pek,
+                    IrInst2::Pop => Instructions::Pop,
+                    IrInst2::Inp => Instructions::Inp,
+                    IrInst2::Add => Instructions::Add,
+                    IrInst2::Sub => Instructions::Sub,
+                    IrInst2::Mul => Instructions::Mul,
+                    IrInst2::Div => Instructions::Div,
+                    IrInst2::Mod => Instructions::Mod,
+                    IrInst2::Abs => Instructions::Abs,
+                    IrInst2::Max => Instructions::Max,
+                    IrInst2::Min => Instructions::Min,
+                    IrInst2::Eql => Instructions::Eql,
+                    IrInst2::Mor => Instructions::Mor,
+                    IrInst2::Les => Instructions::Les,
+                    IrInst2::Jmp(id) => Instructions::Jmp(lblmgr[id]),
+                    IrInst2::Jiz(id) => Instructions::Jiz(lblmgr[id]),
+                    IrInst2::Jnz(id) => Instructions::Jnz(lblmgr[id]),
+                    IrInst2::Label(_) => return None,
+                    IrInst2::Line(span) => {
+                        line = *span;
+                        return None;
+                    }
+                    IrInst2::Epilogue => {
+                        line = None;
+                        return None;
+                    }
+                };
+                spans.push(line);
+                Some(inst)
             })
-            .collect()
+            .collect();
+        (code, SourceMap::new(spans), epilogue)
     }
 }
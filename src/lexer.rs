@@ -0,0 +1,233 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_till, take_while, take_while1},
+    character::complete::{digit0, digit1, one_of},
+    combinator::{opt, recognize},
+    error::{Error, ErrorKind},
+    sequence::{pair, tuple},
+    IResult,
+};
+
+use super::diagnostic::{Diagnostic, Position, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Token {
+    Number(f64),
+    Idnt(String),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Eq,
+    NotEq,
+    Gt,
+    Lt,
+    PlusEq,
+    MinusEq,
+    StarEq,
+    SlashEq,
+    PercentEq,
+    Newline,
+    Comment(String),
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Lexeme {
+    pub(super) token: Token,
+    pub(super) span: Span,
+    /// the token as written, which tells `010` from `10`
+    pub(super) text: String,
+}
+
+impl Token {
+    /// Splits `source` into tokens, skipping horizontal whitespace and `\r`.
+    /// Comments are kept as tokens so that the parser can decide what to do with them.
+    pub(super) fn lex(source: &str) -> Result<Vec<Lexeme>, Vec<Diagnostic>> {
+        let mut lexemes = Vec::new();
+        let mut diagnostics = Vec::new();
+        let mut position = Position { line: 1, column: 1 };
+        let mut input = source;
+        loop {
+            let (rest, space) = Token::space(input).unwrap_or((input, ""));
+            position = position.advance(space);
+            if rest.is_empty() {
+                break;
+            }
+            let start = position;
+            input = match Token::any(rest) {
+                Ok((next, token)) => {
+                    let text = &rest[..rest.len() - next.len()];
+                    position = position.advance(text);
+                    let span = Span {
+                        start,
+                        end: position,
+                    };
+                    lexemes.push(Lexeme {
+                        token,
+                        span,
+                        text: text.to_string(),
+                    });
+                    next
+                }
+                Err(nom::Err::Failure(_)) => {
+                    position = position.advance(rest);
+                    let span = Span {
+                        start,
+                        end: position,
+                    };
+                    diagnostics.push(Diagnostic::error(span, "unterminated block comment"));
+                    ""
+                }
+                Err(_) => {
+                    let c = rest.chars().next().unwrap_or_default();
+                    position = position.advance(&rest[..c.len_utf8()]);
+                    let span = Span {
+                        start,
+                        end: position,
+                    };
+                    diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("unexpected character `{c}`"),
+                    ));
+                    &rest[c.len_utf8()..]
+                }
+            };
+        }
+        if diagnostics.is_empty() {
+            Ok(lexemes)
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    fn space(input: &str) -> IResult<&str, &str> {
+        take_while(|c| c == ' ' || c == '\t' || c == '\r')(input)
+    }
+
+    fn any(input: &str) -> IResult<&str, Token> {
+        alt((
+            Token::line_comment,
+            Token::block_comment,
+            Token::word,
+            Token::symbol,
+        ))(input)
+    }
+
+    /// A number, or a name when its letters and digits run on past the number, like `2x`.
+    fn word(input: &str) -> IResult<&str, Token> {
+        match (Token::number(input), Token::idnt(input)) {
+            (Ok((rest, number)), Ok((longest, _))) if rest.len() <= longest.len() => {
+                Ok((rest, number))
+            }
+            (Ok(number), Err(_)) => Ok(number),
+            (_, idnt) => idnt,
+        }
+    }
+
+    fn line_comment(input: &str) -> IResult<&str, Token> {
+        let (rest, value) =
+            recognize(pair(alt((tag("#"), tag("//"))), take_till(|c| c == '\n')))(input)?;
+        Ok((
+            rest,
+            Token::Comment(value.trim_end_matches('\r').to_string()),
+        ))
+    }
+
+    fn block_comment(input: &str) -> IResult<&str, Token> {
+        let (mut rest, _) = tag("/*")(input)?;
+        let mut depth = 1;
+        while depth > 0 {
+            if let Some(next) = rest.strip_prefix("/*") {
+                depth += 1;
+                rest = next;
+            } else if let Some(next) = rest.strip_prefix("*/") {
+                depth -= 1;
+                rest = next;
+            } else if let Some(c) = rest.chars().next() {
+                rest = &rest[c.len_utf8()..];
+            } else {
+                return Err(nom::Err::Failure(Error::new(input, ErrorKind::TakeUntil)));
+            }
+        }
+        let value = &input[..input.len() - rest.len()];
+        Ok((rest, Token::Comment(value.to_string())))
+    }
+
+    fn number(input: &str) -> IResult<&str, Token> {
+        let (rest, value) = recognize(tuple((
+            alt((
+                recognize(pair(digit1, opt(pair(tag("."), digit0)))),
+                recognize(pair(tag("."), digit1)),
+            )),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+        )))(input)?;
+        // the recognised text is always a valid float literal
+        Ok((rest, Token::Number(value.parse().unwrap_or_default())))
+    }
+
+    fn idnt(input: &str) -> IResult<&str, Token> {
+        let (rest, value) = take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_')(input)?;
+        Ok((rest, Token::Idnt(value.to_string())))
+    }
+
+    fn symbol(input: &str) -> IResult<&str, Token> {
+        let (rest, value) = alt((
+            alt((
+                tag("+="),
+                tag("-="),
+                tag("*="),
+                tag("/="),
+                tag("%="),
+                tag("!="),
+            )),
+            tag("\n"),
+            recognize(one_of("(){},:+-*/%=><")),
+        ))(input)?;
+        let token = match value {
+            "+=" => Token::PlusEq,
+            "-=" => Token::MinusEq,
+            "*=" => Token::StarEq,
+            "/=" => Token::SlashEq,
+            "%=" => Token::PercentEq,
+            "!=" => Token::NotEq,
+            "(" => Token::LParen,
+            ")" => Token::RParen,
+            "{" => Token::LBrace,
+            "}" => Token::RBrace,
+            "," => Token::Comma,
+            ":" => Token::Colon,
+            "+" => Token::Plus,
+            "-" => Token::Minus,
+            "*" => Token::Star,
+            "/" => Token::Slash,
+            "%" => Token::Percent,
+            "=" => Token::Eq,
+            ">" => Token::Gt,
+            "<" => Token::Lt,
+            _ => Token::Newline,
+        };
+        Ok((rest, token))
+    }
+}
+
+impl Position {
+    fn advance(self, text: &str) -> Position {
+        text.chars().fold(self, |pos, c| match c {
+            '\n' => Position {
+                line: pos.line + 1,
+                column: 1,
+            },
+            _ => Position {
+                line: pos.line,
+                column: pos.column + 1,
+            },
+        })
+    }
+}
@@ -1,47 +1,1490 @@
-use std::fmt;
+use std::{cell::Cell, fmt, time::Instant};
 mod ast;
 mod ast_indexed;
+mod bytecode;
+mod cfg;
+mod definite;
+mod diagnostic;
+mod dump;
+mod fold;
+mod format;
 mod ir;
+mod lexer;
+mod liveness;
+mod peephole;
+mod runtime;
+mod session;
+mod source_map;
 
-pub struct Parser(ir::Ir);
+pub use cfg::{Block, Cfg};
+pub use diagnostic::{Diagnostic, Position, Severity, Span};
+pub use runtime::{ErrorKind, Limit, Limits, RuntimeError, VmError};
+pub use session::{Session, SessionError};
+pub use source_map::SourceMap;
 
+pub struct Parser {
+    ast: ast::Ast,
+    indexed: ast_indexed::AstIndexed,
+    ir: ir::Ir,
+    stats: Stats,
+    opt: OptLevel,
+    warnings: Vec<Diagnostic>,
+    limits: Limits,
+    /// variables the program prints at its end for a `Session`, in order
+    globals: Vec<String>,
+}
+
+/// How much work the compiler puts into making the program smaller and faster.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptLevel {
+    /// code the way it is written
+    #[default]
+    O0,
+    /// constant folding and dead code elimination
+    O1,
+    /// everything from `O1` and peephole optimisation of the generated code
+    O2,
+}
+
+/// What to do with a variable that may be read before it is assigned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Uninitialized {
+    /// refuse to compile the program
+    #[default]
+    Error,
+    /// warn about it and start the variable as 0
+    Zero,
+}
+
+/// Settings of the compiler, an `OptLevel` on its own keeps the defaults for the rest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Options {
+    pub opt: OptLevel,
+    pub uninitialized: Uninitialized,
+}
+
+impl From<OptLevel> for Options {
+    fn from(opt: OptLevel) -> Options {
+        Options {
+            opt,
+            ..Options::default()
+        }
+    }
+}
+
+/// Numbers collected while compiling a program.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// variables, parameters and scratch slots of the program
+    pub variables: usize,
+    /// memory cells used at the same time, the size of memory the program needs
+    pub slots: usize,
+}
+
+/// Panics with every diagnostic when `s` does not compile, `Parser::parse` gives them back
+/// instead.
 impl From<&str> for Parser {
     fn from(s: &str) -> Parser {
-        Parser(ir::Ir::from(ast_indexed::AstIndexed::from(ast::Ast::from(
-            s,
-        ))))
+        Parser::parse(s).unwrap_or_else(|errors| {
+            let errors: Vec<_> = errors.iter().map(Diagnostic::to_string).collect();
+            panic!("{}", errors.join("\n"))
+        })
     }
 }
 
 impl fmt::Display for Parser {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        mpl_vm::Program::from((self.0.codegen(), || None)).fmt(f)
+        mpl_vm::Program::from((self.codegen(), || None)).fmt(f)
     }
 }
 
 impl Parser {
+    pub fn parse(s: &str) -> Result<Parser, Vec<Diagnostic>> {
+        Parser::parse_with(s, Options::default())
+    }
+
+    pub fn parse_with(s: &str, options: impl Into<Options>) -> Result<Parser, Vec<Diagnostic>> {
+        Parser::compile(s, options.into(), None)
+    }
+
+    /// Whether `s` stops inside a block or a block comment, so that it needs more lines
+    /// before it can compile.
+    pub fn is_incomplete(s: &str) -> bool {
+        match lexer::Token::lex(s) {
+            Ok(lexemes) => {
+                let depth = lexemes
+                    .iter()
+                    .fold(0, |depth: isize, lexeme| match lexeme.token {
+                        lexer::Token::LBrace => depth + 1,
+                        lexer::Token::RBrace => depth - 1,
+                        _ => depth,
+                    });
+                depth > 0
+            }
+            Err(errors) => errors
+                .iter()
+                .any(|error| error.message == "unterminated block comment"),
+        }
+    }
+
+    /// Whether `s` is one expression on its own, like `f(3)` or `a + 1`, which a prompt
+    /// would show the value of. A line that is a statement as well, like `x = 1`, is not.
+    pub fn is_expression(s: &str) -> bool {
+        use ast::Ast;
+
+        match Ast::try_from(s) {
+            Ok(Ast::Root(inner)) => matches!(
+                &inner[..],
+                [(
+                    _,
+                    Ast::Input | Ast::Abs(_) | Ast::Max(..) | Ast::Min(..) | Ast::Call(..)
+                )]
+            ),
+            Ok(_) => false,
+            Err(_) => matches!(
+                Ast::try_from(format!("print({})\n", s.trim()).as_str()),
+                Ok(Ast::Root(inner)) if matches!(&inner[..], [(_, Ast::Print(args))] if args.len() == 1)
+            ),
+        }
+    }
+
+    /// Compiles `s`, for a `Session` when `globals` holds the variables it has so far. Those
+    /// are stored before the program starts, and every variable of the outermost scope that
+    /// is assigned by then is printed when it ends.
+    fn compile(
+        s: &str,
+        options: Options,
+        globals: Option<&[(String, f64)]>,
+    ) -> Result<Parser, Vec<Diagnostic>> {
+        let Options { opt, uninitialized } = options;
+        let ast = ast::Ast::try_from(s)?;
+        let names: Vec<_> = globals
+            .unwrap_or_default()
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        let (mut indexed, mut warnings, slots) =
+            ast_indexed::AstIndexed::index(ast.clone(), uninitialized, &names)?;
+        if opt >= OptLevel::O1 {
+            indexed = indexed.fold();
+        }
+        let mut ir = ir::Ir::from(indexed.clone());
+        warnings.extend(ir.unreachable_code());
+        warnings.sort_by_key(|warning| warning.span);
+        let globals = match globals {
+            Some(globals) => {
+                let prologue = globals
+                    .iter()
+                    .zip(&slots)
+                    .flat_map(|((_, val), (_, n))| [ir::IrInst::Psh(*val), ir::IrInst::Pta(*n)]);
+                ir.0.splice(0..0, prologue);
+                slots
+            }
+            None => Vec::new(),
+        };
+        if opt >= OptLevel::O1 {
+            ir.remove_dead_code();
+        }
+        if !globals.is_empty() {
+            ir.0.push(ir::IrInst::Epilogue);
+            ir.0.extend(
+                globals
+                    .iter()
+                    .flat_map(|(_, n)| [ir::IrInst::Pfa(*n), ir::IrInst::Pek]),
+            );
+        }
+        let stats = ir.allocate()?;
+        let globals = globals.into_iter().map(|(name, _)| name).collect();
+        Ok(Parser {
+            ast,
+            indexed,
+            ir,
+            stats,
+            opt,
+            warnings,
+            limits: Limits::default(),
+            globals,
+        })
+    }
+
+    // The dumps below are meant for people chasing a miscompilation. Their format only
+    // changes along with the stages they show.
+
+    /// The syntax tree of the program as an indented tree, one node per line.
+    pub fn dump_ast(&self) -> String {
+        self.ast.dump()
+    }
+
+    /// The syntax tree with every variable turned into its slot, after constant folding.
+    pub fn dump_indexed(&self) -> String {
+        self.indexed.dump()
+    }
+
+    /// The intermediate code with memory addresses, labels at the start of their line.
+    pub fn dump_ir(&self) -> String {
+        self.ir.dump()
+    }
+
+    /// The code for `mpl_vm` after the peephole pass, with label names in place of the
+    /// addresses they turn into.
+    pub fn dump_code(&self) -> String {
+        dump::dump_code(&self.ir.code(self.opt >= OptLevel::O2))
+    }
+
+    /// The program for `mpl_vm` as bytes: `MPL` and a version byte of 1, the number of
+    /// instructions as a little endian `u32`, then every instruction as an opcode byte
+    /// followed by its operand. The opcodes count up from 0 for `psh` in the order `psh pop
+    /// pek inp sap pfa pta add sub mul div mod abs max min eql mor les jmp jiz jnz`. `psh`
+    /// takes a little endian `f64`, `sap` a byte and the jumps a little endian `u32` index.
+    pub fn bytecode(&self) -> Vec<u8> {
+        bytecode::encode(&self.codegen())
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn cfg(&self) -> Cfg {
+        Cfg::from(&self.ir)
+    }
+
+    /// Bounds every later run of the program.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Where the instructions of the compiled program come from in the source.
+    pub fn source_map(&self) -> SourceMap {
+        self.ir.codegen(self.opt >= OptLevel::O2).1
+    }
+
+    /// Problems that do not stop the program from compiling.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    fn codegen(&self) -> Vec<mpl_vm::Instructions> {
+        self.ir.codegen(self.opt >= OptLevel::O2).0
+    }
+
+    /// Runs the program and prints every value it prints on its own line of stdout.
     #[allow(dead_code)]
     pub fn eval<F: FnMut() -> Option<f64>>(self, input: &mut F, debug: bool) -> Option<()> {
-        for res in mpl_vm::Program::from((self.0.codegen(), input, debug)) {
-            if let Some(val) = res.ok()? {
-                println!("{val}");
+        self.eval_with(input, |val| println!("{val}"), debug).ok()
+    }
+
+    /// Runs the program, handing every value it prints to `output`. Stops with
+    /// `ErrorKind::LimitExceeded` when the program goes past its `Limits`.
+    pub fn eval_with<I, O>(&self, input: I, output: O, debug: bool) -> Result<(), RuntimeError>
+    where
+        I: FnMut() -> Option<f64>,
+        O: FnMut(f64),
+    {
+        self.execute(input, output, debug).map(drop)
+    }
+
+    /// Runs the program like `eval_with`, then the epilogue, and gives back the values of
+    /// `globals` it prints. The epilogue counts against none of the `Limits` but the time.
+    fn execute<I, O>(
+        &self,
+        mut input: I,
+        mut output: O,
+        debug: bool,
+    ) -> Result<Vec<f64>, RuntimeError>
+    where
+        I: FnMut() -> Option<f64>,
+        O: FnMut(f64),
+    {
+        let Limits {
+            steps,
+            time,
+            inputs: max_inputs,
+            outputs: max_outputs,
+        } = self.limits;
+        let (code, source_map, epilogue) = self.ir.codegen(self.opt >= OptLevel::O2);
+        let epilogue = epilogue.unwrap_or(code.len());
+        let mut tracer = runtime::Tracer::new(code);
+        let mut reads = 0;
+        // the VM only learns that there is no input left, so the limit is noted on the side
+        let too_many_reads = Cell::new(false);
+        let input = || {
+            if max_inputs.is_some_and(|max| reads >= max) {
+                too_many_reads.set(true);
+                return None;
             }
+            reads += 1;
+            input()
+        };
+        let start = Instant::now();
+        let mut step = 0;
+        let mut outputs = 0;
+        let mut globals = Vec::new();
+        for res in mpl_vm::Program::from((tracer.probed(), input, debug)) {
+            // neither the `pek`s the tracer adds nor the epilogue are part of the program
+            let counted = !tracer.probing() && tracer.pc < epilogue;
+            let limit = if too_many_reads.get() {
+                max_inputs.map(Limit::Inputs)
+            } else if counted && steps.is_some_and(|max| step >= max) {
+                steps.map(Limit::Steps)
+            } else if time.is_some_and(|max| start.elapsed() > max) {
+                time.map(Limit::Time)
+            } else if counted
+                && max_outputs.is_some_and(|max| outputs >= max)
+                && matches!(res, Ok(Some(_)))
+            {
+                max_outputs.map(Limit::Outputs)
+            } else {
+                None
+            };
+            let kind = match (limit, res) {
+                (Some(limit), _) => ErrorKind::LimitExceeded(limit),
+                (None, Err(error)) => ErrorKind::Vm(error),
+                (None, Ok(val)) => {
+                    step += u64::from(counted);
+                    let pc = tracer.pc;
+                    match tracer.step(val) {
+                        Some(val) if pc >= epilogue => globals.push(val),
+                        Some(val) => {
+                            outputs += 1;
+                            output(val)
+                        }
+                        None => (),
+                    }
+                    continue;
+                }
+            };
+            let span = source_map.span(tracer.pc);
+            return Err(RuntimeError::new(kind, tracer.pc, span));
         }
+        Ok(globals)
+    }
+
+    /// Runs the program on `inputs` and collects what it prints.
+    pub fn run_collect(&self, inputs: &[f64]) -> Result<Vec<f64>, RuntimeError> {
+        let mut inputs = inputs.iter().copied();
+        let mut outputs = Vec::new();
+        self.eval_with(|| inputs.next(), |val| outputs.push(val), false)?;
+        Ok(outputs)
+    }
+}
 
-        Some(())
+/// `s` in canonical form: blocks indented by four spaces, single spaces around operators,
+/// only the parentheses the meaning needs and `x += e` for `x = x + e`. Comments are kept.
+/// Parsing the result gives the same program as parsing `s`.
+pub fn format(s: &str) -> Result<String, Diagnostic> {
+    match ast::Ast::parse(s, true) {
+        Ok(ast) => Ok(ast.format()),
+        Err(mut errors) => Err(errors.remove(0)),
     }
 }
 
 #[cfg(test)]
 mod test {
+    fn run(source: &str, inputs: &[f64]) -> Vec<f64> {
+        run_with(source, inputs, super::OptLevel::O0)
+    }
+
+    fn run_with(source: &str, inputs: &[f64], options: impl Into<super::Options>) -> Vec<f64> {
+        let program = super::Parser::parse_with(source, options).unwrap();
+        program.run_collect(inputs).unwrap()
+    }
+
     #[test]
     fn print_2_plus_2() {
         use super::Parser;
 
         let source = "print(2 + 2)\n";
 
-        let program = Parser::from(source);
+        let program = Parser::parse(source).unwrap();
 
         assert!(program.to_string() == "psh 2\npsh 2\nadd\npek\npop\n");
     }
+
+    #[test]
+    fn diagnostics_instead_of_panics() {
+        use super::{Parser, Position};
+
+        let errors = Parser::parse("a = 1\nb = = 2\nprint(a)\n").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.start, Position { line: 2, column: 1 });
+
+        let errors = Parser::parse("while a {\nprint(c)\n}\n}\ngoto nowhere\n")
+            .err()
+            .unwrap();
+        let messages: Vec<_> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "uninitialized variable `a`",
+                "uninitialized variable `c`",
+                "there are more ends then blocks",
+                "unknown label `nowhere`",
+            ]
+        );
+        assert_eq!(errors[3].span.start, Position { line: 5, column: 1 });
+
+        let errors = Parser::parse("if 1 {\n").err().unwrap();
+        assert_eq!(errors[0].message, "unclosed block");
+        assert!(std::panic::catch_unwind(|| Parser::from("if 1 {\n")).is_err());
+    }
+
+    #[test]
+    fn operator_precedence() {
+        use super::Parser;
+
+        let program = Parser::parse("print(1 + 2 * 3 - 4)\n").unwrap();
+        assert_eq!(
+            program.to_string(),
+            "psh 1\npsh 2\npsh 3\nmul\nadd\npsh 4\nsub\npek\npop\n"
+        );
+
+        let program = Parser::parse("print(-(1 - 2) < 2 % 3)\n").unwrap();
+        assert_eq!(
+            program.to_string(),
+            "psh -1\npsh 1\npsh 2\nsub\nmul\npsh 2\npsh 3\nmod\nles\npek\npop\n"
+        );
+
+        assert!(Parser::parse("a = 1\nlbl:\ngoto lbl if a - 1 = 0\n").is_ok());
+    }
+
+    #[test]
+    fn layout_insensitive() {
+        use super::Parser;
+
+        let canonical = Parser::parse("a = 1\nb = a + 2\nprint(b, -a)\n").unwrap();
+        let sloppy = Parser::parse("\r\n  a=1\r\n\r\n\tb  =  a+2   \r\nprint( b,-a )").unwrap();
+        assert_eq!(canonical.to_string(), sloppy.to_string());
+
+        let errors = Parser::parse("a = 1 @\n").err().unwrap();
+        assert_eq!(errors[0].message, "unexpected character `@`");
+        assert_eq!(errors[0].span.start.column, 7);
+
+        // names may start with a digit or be digits only, as they always could
+        assert!(Parser::parse("2x = 1\nx = 2x + 1\n10:\ngoto 10\n").is_ok());
+        let errors = Parser::parse("10:\ngoto 010\n").err().unwrap();
+        assert_eq!(errors[0].message, "unknown label `010`, did you mean `10`?");
+        assert!(Parser::parse("1e1:\ngoto 1e1\n").is_ok());
+    }
+
+    #[test]
+    fn comments() {
+        use super::{ast::Ast, Parser};
+
+        let source =
+            "# header\na = 1 // one\n/* a /* nested */\nblock */ print(a /* inline */ + 1)\n";
+        let canonical = Parser::parse("a = 1\nprint(a + 1)\n").unwrap();
+        assert_eq!(
+            Parser::parse(source).unwrap().to_string(),
+            canonical.to_string()
+        );
+
+        let Ok(Ast::Root(inner)) = Ast::parse(source, true) else {
+            panic!("source should parse");
+        };
+        let comments: Vec<_> = inner
+            .iter()
+            .filter_map(|(_, inst)| match inst {
+                Ast::Comment(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            comments,
+            [
+                "# header",
+                "// one",
+                "/* a /* nested */\nblock */",
+                "/* inline */"
+            ]
+        );
+
+        let errors = Parser::parse("a = 1 /* open\n").err().unwrap();
+        assert_eq!(errors[0].message, "unterminated block comment");
+    }
+
+    #[test]
+    fn else_chains() {
+        use super::Parser;
+
+        let source = "n = input()
+while n > 0 {
+    x = input()
+    if x < 0 {
+        print(-1)
+    } else if x = 0 {
+        print(0)
+    } else if not x - 1 {
+        print(1)
+    } else {
+        i = 0
+        while i < x {
+            if i % 2 {
+                i += 1
+            } else {
+                i += 1
+            }
+        }
+        print(i)
+    }
+    n -= 1
+}
+";
+        assert_eq!(
+            run(source, &[4.0, -5.0, 0.0, 1.0, 3.0]),
+            [-1.0, 0.0, 1.0, 3.0]
+        );
+
+        let source = "x = input()\nif not x {\nprint(1)\n} else {\nprint(2)\n}\nprint(3)\n";
+        assert_eq!(run(source, &[0.0]), [1.0, 3.0]);
+        assert_eq!(run(source, &[7.0]), [2.0, 3.0]);
+
+        let errors = Parser::parse("while 1 {\n} else {\n}\n").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "`else` without `if`");
+    }
+
+    #[test]
+    fn functions() {
+        use super::Parser;
+
+        let source = "fn fact(n) {
+    if n < 2 {
+        return 1
+    }
+    return n * fact(n - 1)
+}
+fn fib(n) {
+    if n < 2 {
+        return n
+    }
+    return fib(n - 1) + fib(n - 2)
+}
+fn show(a, b) {
+    print(a - b)
+}
+print(fact(5), fib(10))
+show(even(7), 10)
+fn even(n) {
+    if not n {
+        return 1
+    }
+    return odd(n - 1)
+}
+fn odd(n) {
+    if not n {
+        return 0
+    }
+    return even(n - 1)
+}
+";
+        assert_eq!(run(source, &[]), [120.0, 55.0, -10.0]);
+
+        let errors = Parser::parse("fn f(a) {\nfn g() {\n}\n}\nreturn 1\nprint(f(1, 2), h())\n")
+            .err()
+            .unwrap();
+        let messages: Vec<_> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "functions can only be defined at the top level",
+                "`return` outside of a function",
+                "function `f` takes 1 arguments but 2 were given",
+                "unknown function `h`",
+            ]
+        );
+
+        // every function has labels of its own that a `goto` cannot enter or leave
+        let source = "fn f(n) {\nagain:\nn -= 1\ngoto again if n < 1 = 0\nreturn n\n}\n\
+            fn g() {\nagain:\nreturn 2\n}\nagain:\nprint(f(3) + g())\n";
+        assert_eq!(run(source, &[]), [2.0]);
+        let errors = Parser::parse("goto inside\nfn f(a) {\ninside:\nreturn a\n}\nprint(1)\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            errors[0].message,
+            "cannot jump to label `inside` inside of function `f`"
+        );
+        let errors = Parser::parse("fn f(a) {\ngoto out\nreturn a\n}\nprint(f(1))\nout:\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            errors[0].message,
+            "cannot jump to label `out` out of function `f`"
+        );
+        assert_eq!(errors[0].span.start.line, 2);
+
+        let errors = Parser::parse("fn f(a, a) {\nreturn a\n}\nprint(f(1, 2))\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            errors[0].message,
+            "parameter `a` of function `f` is given more than once"
+        );
+        let errors = Parser::parse("fn print(a) {\nreturn a\n}\nfn input() {\nreturn 1\n}\n")
+            .err()
+            .unwrap();
+        let messages: Vec<_> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "`print` is a built-in function and cannot be defined again",
+                "`input` is a built-in function and cannot be defined again",
+            ]
+        );
+    }
+
+    #[test]
+    fn block_scoping() {
+        use super::Parser;
+        use mpl_vm::Instructions;
+
+        let source = "a = 1
+x = 0
+while x < 3 {
+    y = x * 10
+    let a = y
+    x += 1
+}
+if 1 {
+    let a = 2
+    z = a + 5
+    print(a, z)
+}
+print(a)
+";
+        assert_eq!(run(source, &[]), [2.0, 7.0, 1.0]);
+
+        let program = Parser::parse(source).unwrap();
+        let slots = program.codegen().iter().fold(0, |slots, inst| match inst {
+            Instructions::Sap(n) => slots.max(*n + 1),
+            _ => slots,
+        });
+        assert_eq!(slots, 3);
+        assert_eq!(program.stats().slots, 3);
+
+        let errors = Parser::parse("if 1 {\nb = 1\n}\nprint(b, c)\n")
+            .err()
+            .unwrap();
+        let messages: Vec<_> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "use of variable `b` out of scope",
+                "uninitialized variable `c`"
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_limit() {
+        use super::Parser;
+
+        let mut globals: String = (0..300).map(|i| format!("v{i} = {i}\n")).collect();
+        let names: Vec<_> = (0..300).map(|i| format!("v{i}")).collect();
+        globals += &format!("print({})\n", names.join(", "));
+        let errors = Parser::parse(&globals).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "program needs more than 256 memory cells"
+        );
+        assert_eq!(errors[0].span.start.line, 257);
+
+        let scoped: String = (0..300)
+            .map(|i| format!("if 1 {{\nv{i} = {i}\n}}\n"))
+            .collect();
+        assert!(Parser::parse(&scoped).is_ok());
+    }
+
+    #[test]
+    fn liveness_allocation() {
+        use super::{Parser, Stats};
+
+        let sequential: String = (0..300)
+            .map(|i| format!("v{i} = input()\nprint(v{i} * 2)\n"))
+            .collect();
+        let program = Parser::parse(&sequential).unwrap();
+        assert_eq!(
+            program.stats(),
+            Stats {
+                variables: 300,
+                slots: 1
+            }
+        );
+        let inputs: Vec<_> = (0..300).map(f64::from).collect();
+        let doubled: Vec<_> = (0..300).map(|i| f64::from(i) * 2.0).collect();
+        assert_eq!(run(&sequential, &inputs), doubled);
+
+        // `a` and `b` share a cell, `i` is live across the whole loop
+        let source = "a = 0
+i = 0
+while i < 3 {
+    b = a + 1
+    print(b)
+    a = b * 2
+    i += 1
+}
+";
+        assert_eq!(Parser::parse(source).unwrap().stats().slots, 2);
+        assert_eq!(run(source, &[]), [1.0, 3.0, 7.0]);
+    }
+
+    #[test]
+    fn constant_folding() {
+        use super::{OptLevel, Parser};
+
+        let program = Parser::parse_with("print(2 + 2)\n", OptLevel::O1).unwrap();
+        assert_eq!(program.to_string(), "psh 4\npek\npop\n");
+
+        let program =
+            Parser::parse_with("print(-(1 - 2) < 2 % 3, max(1, 2) * 3 = 6)\n", OptLevel::O1)
+                .unwrap();
+        assert_eq!(program.to_string(), "psh 1\npek\npop\npsh 1\npek\npop\n");
+
+        let program = Parser::parse_with("print(1 / 0)\n", OptLevel::O1).unwrap();
+        assert_eq!(program.to_string(), "psh 1\npsh 0\ndiv\npek\npop\n");
+
+        let folded = Parser::parse_with(
+            "a = input()\nprint(a * 1, 1 * a - 0, a / 1 + 0)\n",
+            OptLevel::O1,
+        )
+        .unwrap();
+        let plain = Parser::parse("a = input()\nprint(a, a, a + 0)\n").unwrap();
+        assert_eq!(folded.to_string(), plain.to_string());
+
+        let source = "fn f(x) {
+    return x * (2 + 3) - 0
+}
+x = input()
+while x > 10 - 2 * 5 {
+    print(f(x) + abs(1 - 4), min(x, 2 + 1))
+    x -= 1 * 1
+}
+";
+        let folded = Parser::parse_with(source, OptLevel::O1).unwrap();
+        assert!(folded.to_string().len() < Parser::parse(source).unwrap().to_string().len());
+        assert_eq!(run(source, &[2.0]), [13.0, 2.0, 8.0, 1.0]);
+        assert_eq!(run_with(source, &[2.0], OptLevel::O1), run(source, &[2.0]));
+    }
+
+    #[test]
+    fn peephole() {
+        use super::{OptLevel, Parser};
+
+        let program = Parser::parse_with("a = 1\nprint(a)\n", OptLevel::O2).unwrap();
+        assert_eq!(program.to_string(), "psh 1\npek\npop\n");
+
+        let program = Parser::parse_with("if 0 {\n}\nprint(2)\n", OptLevel::O2).unwrap();
+        assert_eq!(program.to_string(), "psh 2\npek\npop\n");
+
+        let sources: [(&str, &[f64]); 5] = [
+            (
+                "n = input()\nwhile n > 0 {\nx = n * 2\nprint(x, x + 1)\nn -= 1\n}\n",
+                &[3.0],
+            ),
+            (
+                "a = input()\nb = input()\nswap a and b\nprint(a - b)\nc = a\nprint(c)\n",
+                &[1.0, 5.0],
+            ),
+            (
+                "i = 0\nstart:\ni += 1\ngoto done if i - 5 = 0\ngoto start\ndone:\nprint(i)\n",
+                &[],
+            ),
+            (
+                "fn f(n) {\nif n < 2 {\nreturn n\n}\nreturn f(n - 1) + f(n - 2)\n}\nprint(f(input()))\n",
+                &[12.0],
+            ),
+            (
+                "x = input()\nif x = 1 {\nprint(1)\n} else if x = 2 {\nprint(2)\n} else {\nprint(3)\n}\n",
+                &[2.0],
+            ),
+        ];
+        for (source, inputs) in sources {
+            let plain = Parser::parse(source).unwrap().to_string();
+            let optimised = Parser::parse_with(source, OptLevel::O2)
+                .unwrap()
+                .to_string();
+            assert!(
+                optimised.lines().count() < plain.lines().count(),
+                "{source}"
+            );
+            assert_eq!(
+                run_with(source, inputs, OptLevel::O2),
+                run(source, inputs),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn dead_code() {
+        use super::{OptLevel, Parser, Position, Severity};
+
+        let source = "goto end\nprint(1)\nprint(2)\nend:\nprint(3)\n";
+        let program = Parser::parse(source).unwrap();
+        let warnings = program.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, Severity::Warning);
+        assert_eq!(warnings[0].message, "unreachable code");
+        assert_eq!(warnings[0].span.start, Position { line: 2, column: 1 });
+        assert_eq!(warnings[0].span.end.line, 3);
+        let program = Parser::parse_with(source, OptLevel::O2).unwrap();
+        assert_eq!(program.to_string(), "psh 3\npek\npop\n");
+        assert_eq!(run_with(source, &[], OptLevel::O1), [3.0]);
+
+        let source = "if 1 - 1 {\nprint(1)\n}\nwhile 1 {\nprint(2)\ngoto out\n}\nout:\n";
+        // the loop never gets back to its condition on line 7, `1 - 1` is only folded at O1
+        let warnings = Parser::parse(source).unwrap().warnings().to_vec();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].span.start.line, 7);
+        let program = Parser::parse_with(source, OptLevel::O1).unwrap();
+        let lines: Vec<_> = program
+            .warnings()
+            .iter()
+            .map(|warning| warning.span.start.line)
+            .collect();
+        assert_eq!(lines, [2, 7]);
+        assert!(!program.to_string().contains("psh 1\n"));
+        assert_eq!(run_with(source, &[], OptLevel::O1), [2.0]);
+
+        let source = "while 1 {\nprint(input())\n}\nprint(0)\n";
+        let warnings = Parser::parse(source).unwrap().warnings().to_vec();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].span.start.line, 4);
+
+        let source = "fn f(n) {\nif n {\nreturn 1\n} else {\nreturn 2\n}\n}\nprint(f(0))\n";
+        assert!(Parser::parse(source).unwrap().warnings().is_empty());
+        assert_eq!(run_with(source, &[], OptLevel::O1), [2.0]);
+
+        // `f` is only called by `g`, which is never called, so `g` alone gets a warning
+        let source = "fn f(a) {\nb = a + 1\nreturn b\n}\nfn g() {\nreturn f(1)\n}\nprint(2)\n";
+        let warnings = Parser::parse(source).unwrap().warnings().to_vec();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "function `g` is never called");
+        assert_eq!(warnings[0].span.start, Position { line: 5, column: 1 });
+        assert_eq!(run_with(source, &[], OptLevel::O1), [2.0]);
+    }
+
+    #[test]
+    fn control_flow_graph() {
+        use super::Parser;
+
+        let source = "i = 0\nwhile i < 3 {\ni += 1\n}\nprint(i)\n";
+        let cfg = Parser::parse(source).unwrap().cfg();
+        assert_eq!(cfg.blocks.len(), 3);
+        assert_eq!(cfg.blocks[0].successors, [1, 2]);
+        assert_eq!(cfg.blocks[1].successors, [2, 1]);
+        assert_eq!(cfg.blocks[1].predecessors, [0, 1]);
+        assert_eq!(cfg.blocks[2].predecessors, [0, 1]);
+        let lines: Vec<_> = cfg.blocks[1]
+            .spans
+            .iter()
+            .map(|span| span.start.line)
+            .collect();
+        assert_eq!(lines, [2, 3, 4]);
+        assert_eq!(cfg.blocks[2].code.last().unwrap(), "pek");
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("    b1 -> b1;\n"));
+        assert!(dot.contains("    b0 [label=\"lines 1-2\\lpsh 0\\lpta 0\\l"));
+    }
+
+    #[test]
+    fn labels() {
+        use super::Parser;
+
+        let source = "fn f(n) {
+    return n + 1
+}
+x = 0
+goto end_program
+call_0:
+x += 10
+end_program:
+if x < 1 {
+    x = f(x)
+    goto call_0
+}
+print(x)
+";
+        assert_eq!(run(source, &[]), [11.0]);
+
+        let errors = Parser::parse("a:\nb:\na:\ngoto b\n").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "label `a` is defined more than once");
+        assert_eq!(errors[0].span.start.line, 3);
+    }
+
+    #[test]
+    fn goto_targets() {
+        use super::{Parser, Severity};
+
+        let errors = Parser::parse("start:\ngoto strat\ngoto finish\n")
+            .err()
+            .unwrap();
+        let messages: Vec<_> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "unknown label `strat`, did you mean `start`?",
+                "unknown label `finish`"
+            ]
+        );
+        assert_eq!(errors[0].span.start.line, 2);
+
+        let program =
+            Parser::parse("x = 1\nunused:\nloop:\nx += 1\ngoto loop if x - 3 = 0\n").unwrap();
+        let warnings = program.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, Severity::Warning);
+        assert_eq!(warnings[0].message, "label `unused` is never jumped to");
+        assert_eq!(warnings[0].span.start.line, 2);
+    }
+
+    #[test]
+    fn definite_assignment() {
+        use super::{Options, Parser, Severity, Uninitialized};
+
+        let source = "i = 0
+goto set
+show:
+print(x)
+set:
+x = i * 2
+i += 1
+goto show if 4 - i != 0
+";
+        assert_eq!(run(source, &[]), [0.0, 2.0, 4.0]);
+
+        let source = "i = input()
+goto skip if i = 0
+x = 1
+skip:
+print(x, y)
+";
+        let errors = Parser::parse(source).err().unwrap();
+        let messages: Vec<_> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "variable `x` may be used before it is assigned",
+                "uninitialized variable `y`"
+            ]
+        );
+        assert_eq!(errors[0].span.start.line, 5);
+
+        let zero = Options {
+            uninitialized: Uninitialized::Zero,
+            ..Options::default()
+        };
+        let program = Parser::parse_with(source, zero).unwrap();
+        let warnings = program.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings
+            .iter()
+            .all(|warning| warning.severity == Severity::Warning));
+        assert_eq!(run_with(source, &[0.0], zero), [0.0, 0.0]);
+        assert_eq!(run_with(source, &[3.0], zero), [1.0, 0.0]);
+
+        let source = "fn count(n) {
+    goto done if n = 0
+    total = count(n - 1) + 1
+    done:
+    return total
+}
+print(count(3))
+";
+        assert!(Parser::parse(source).is_err());
+        assert_eq!(run_with(source, &[], zero), [3.0]);
+    }
+
+    #[test]
+    fn output_sink() {
+        use super::Parser;
+
+        let program = Parser::parse("a = input()\nprint(a, a * 2)\nprint(a + 1)\n").unwrap();
+        assert_eq!(program.run_collect(&[3.0]).unwrap(), vec![3.0, 6.0, 4.0]);
+
+        let mut printed = String::new();
+        let mut inputs = [5.0].into_iter();
+        program
+            .eval_with(|| inputs.next(), |val| printed += &format!("{val};"), false)
+            .unwrap();
+        assert_eq!(printed, "5;10;6;");
+
+        let program = Parser::parse("print(1)\nprint(input())\nprint(2)\n").unwrap();
+        let mut printed = Vec::new();
+        let res = program.eval_with(|| None, |val| printed.push(val), false);
+        assert!(res.is_err());
+        assert_eq!(printed, [1.0]);
+        assert!(program.run_collect(&[]).is_err());
+    }
+
+    #[test]
+    fn runtime_errors() {
+        use super::{OptLevel, Parser, Position};
+
+        let source = "x = 0
+while x < 3 {
+    x += input()
+    print(x)
+}
+fn twice(a) {
+    return a * 2 + input()
+}
+print(twice(x))
+";
+        for opt in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let program = Parser::parse_with(source, opt).unwrap();
+            let error = program.run_collect(&[1.0, 1.0]).unwrap_err();
+            let span = error.span.unwrap();
+            assert_eq!(span.start, Position { line: 3, column: 5 });
+            assert!(matches!(
+                program.codegen()[error.index],
+                mpl_vm::Instructions::Inp
+            ));
+
+            let error = program.run_collect(&[1.0, 1.0, 1.0]).unwrap_err();
+            assert_eq!(error.span.unwrap().start.line, 7);
+            assert!(error.to_string().starts_with("7:5: runtime error: "));
+            assert_eq!(
+                program.run_collect(&[1.0, 1.0, 1.0, 4.0]).unwrap(),
+                vec![1.0, 2.0, 3.0, 10.0]
+            );
+        }
+    }
+
+    #[test]
+    fn source_map() {
+        use super::{OptLevel, Parser, Position, Span};
+
+        let program = Parser::parse("a = 1\nprint(a + 2)\n").unwrap();
+        let source_map = program.source_map();
+        assert_eq!(source_map.len(), program.codegen().len());
+        assert_eq!(source_map.instructions(1), [0, 1, 2]);
+        assert_eq!(source_map.instructions(2), [3, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            source_map.span(3),
+            Some(Span {
+                start: Position { line: 2, column: 1 },
+                end: Position {
+                    line: 2,
+                    column: 13
+                },
+            })
+        );
+        assert_eq!(source_map.span(9), None);
+
+        let source = "fn f(n) {
+    return n * 2
+}
+x = input()
+print(f(x) + f(1))
+";
+        for opt in [OptLevel::O0, OptLevel::O2] {
+            let program = Parser::parse_with(source, opt).unwrap();
+            let source_map = program.source_map();
+            assert_eq!(source_map.len(), program.codegen().len());
+            // the jump over the function and the dispatcher that returns from it
+            assert_eq!(source_map.span(0), None);
+            assert_eq!(source_map.span(source_map.len() - 1), None);
+            assert!([2, 4, 5]
+                .iter()
+                .all(|line| !source_map.instructions(*line).is_empty()));
+        }
+    }
+
+    #[test]
+    fn limits() {
+        use super::{ErrorKind, Limit, Limits, Parser};
+        use std::time::Duration;
+
+        let mut program = Parser::parse("x = 0\nwhile 1 {\nx += 1\n}\n").unwrap();
+        program.set_limits(Limits {
+            steps: Some(1000),
+            ..Limits::default()
+        });
+        let error = program.run_collect(&[]).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ErrorKind::LimitExceeded(Limit::Steps(1000))
+        ));
+        assert!((2..=4).contains(&error.span.unwrap().start.line));
+
+        let time = Duration::from_millis(20);
+        program.set_limits(Limits {
+            time: Some(time),
+            ..Limits::default()
+        });
+        let error = program.run_collect(&[]).unwrap_err();
+        assert!(
+            matches!(error.kind, ErrorKind::LimitExceeded(Limit::Time(limit)) if limit == time)
+        );
+
+        let mut program = Parser::parse("while 1 {\nprint(input())\n}\n").unwrap();
+        program.set_limits(Limits {
+            inputs: Some(2),
+            ..Limits::default()
+        });
+        let mut printed = Vec::new();
+        let error = program
+            .eval_with(|| Some(1.0), |val| printed.push(val), false)
+            .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ErrorKind::LimitExceeded(Limit::Inputs(2))
+        ));
+        assert_eq!(error.span.unwrap().start.line, 2);
+        assert_eq!(printed, [1.0, 1.0]);
+
+        program.set_limits(Limits {
+            outputs: Some(3),
+            ..Limits::default()
+        });
+        let mut printed = Vec::new();
+        let error = program
+            .eval_with(|| Some(2.0), |val| printed.push(val), false)
+            .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ErrorKind::LimitExceeded(Limit::Outputs(3))
+        ));
+        assert_eq!(printed, [2.0, 2.0, 2.0]);
+        assert_eq!(
+            error.to_string(),
+            "2:1: limit exceeded: program printed more than 3 values"
+        );
+    }
+
+    #[test]
+    fn session() {
+        use super::{Limits, OptLevel, Session, SessionError};
+
+        for opt in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let mut session = Session::new(opt);
+            assert_eq!(session.run_collect("rate = 0.5\n", &[]).unwrap(), vec![]);
+            assert_eq!(session.get_var("rate"), Some(0.5));
+
+            let source = "total = 0
+while total < 10 {
+    total += input() * rate
+}
+print(total)
+";
+            assert_eq!(
+                session.run_collect(source, &[8.0, 8.0, 8.0]).unwrap(),
+                vec![12.0]
+            );
+            assert_eq!(session.get_var("total"), Some(12.0));
+
+            session.set_var("rate", 2.0);
+            session.set_var("bonus", 1.0);
+            assert_eq!(
+                session
+                    .run_collect("total += bonus\nprint(total * rate)\n", &[])
+                    .unwrap(),
+                vec![26.0]
+            );
+            assert_eq!(
+                session.vars().collect::<Vec<_>>(),
+                [("rate", 2.0), ("total", 13.0), ("bonus", 1.0)]
+            );
+
+            let Err(SessionError::Compile(errors)) = session.run_collect("print(missing)\n", &[])
+            else {
+                panic!("`missing` is not defined");
+            };
+            assert_eq!(errors[0].message, "uninitialized variable `missing`");
+            assert!(matches!(
+                session.run_collect("total = 0\nprint(input())\n", &[]),
+                Err(SessionError::Runtime(_))
+            ));
+            assert_eq!(session.get_var("total"), Some(13.0));
+
+            // `z` is not assigned when the jump is taken, so the session does not keep it
+            let source = "goto e if input() = 0\nz = 4\ne:\n";
+            for input in [0.0, 1.0] {
+                session.run_collect(source, &[input]).unwrap();
+                assert_eq!(session.get_var("z"), None);
+            }
+
+            let mut session = Session::new(opt);
+            session.run_collect("x = 5\ny = 7\n", &[]).unwrap();
+            let source = "abs(3)\nmax(x, y)\nmin(x, 1)\ninput()\n";
+            assert_eq!(session.run_collect(source, &[1.0]).unwrap(), vec![]);
+            assert_eq!(session.vars().collect::<Vec<_>>(), [("x", 5.0), ("y", 7.0)]);
+        }
+
+        // saving the variables counts against neither the steps nor the outputs
+        let mut session = Session::new(OptLevel::O0);
+        session.set_limits(Limits {
+            steps: Some(3),
+            outputs: Some(0),
+            ..Limits::default()
+        });
+        assert_eq!(session.run_collect("a = 1\n", &[]).unwrap(), vec![]);
+        assert_eq!(session.get_var("a"), Some(1.0));
+    }
+
+    #[test]
+    fn is_expression() {
+        use super::Parser;
+
+        for s in ["f(3)", "abs(-3)", "input()", "x + 1", " max(x, 2) "] {
+            assert!(Parser::is_expression(s), "{s}");
+        }
+        for s in ["x = 1", "print(x)", "goto end", "x +", ""] {
+            assert!(!Parser::is_expression(s), "{s}");
+        }
+    }
+
+    #[test]
+    fn emit() {
+        use super::Parser;
+
+        let program = Parser::parse("a = 2\nprint(a)\n").unwrap();
+        let bytecode = program.bytecode();
+        assert_eq!(bytecode[..8], [b'M', b'P', b'L', 1, 7, 0, 0, 0]);
+        assert_eq!(bytecode[8], 0);
+        assert_eq!(bytecode[9..17], 2.0f64.to_le_bytes());
+        assert_eq!(bytecode.len(), 8 + 9 + 2 + 1 + 2 + 1 + 1 + 1);
+        assert_eq!(
+            program.dump_ir(),
+            "    # line 1\n    psh 2\n    pta 0\n    # line 2\n    pfa 0\n    pek\n"
+        );
+
+        let source = "a = 1\nprint(a, b)\n";
+        let errors = Parser::parse(source).err().unwrap();
+        assert_eq!(
+            errors[0].render(source, "test.mpl"),
+            "error: uninitialized variable `b`
+ --> test.mpl:2:1
+  |
+2 | print(a, b)
+  | ^^^^^^^^^^^
+"
+        );
+    }
+
+    #[test]
+    fn dumps() {
+        use super::Parser;
+
+        let program = Parser::parse("a = 1 # one\nwhile a < 3 {\n    a += 1\n}\n").unwrap();
+        assert_eq!(
+            program.dump_ast(),
+            "root
+  1:1 assign a
+    value 1
+  2:1 while_not
+    les
+      idnt a
+      value 3
+  3:5 assign a
+    add
+      idnt a
+      value 1
+  4:1 end
+"
+        );
+        assert!(program.dump_indexed().starts_with(
+            "root
+  line 1:1
+  assign 0
+    value 1
+  line 2:1
+  root
+    goto_if end_while$0
+"
+        ));
+        assert_eq!(
+            program.dump_ir(),
+            "    # line 1
+    psh 1
+    pta 0
+    # line 2
+    pfa 0
+    psh 3
+    les
+    jiz end_while$0
+while$0:
+    # line 3
+    pfa 0
+    psh 1
+    add
+    pta 0
+    # line 4
+    pfa 0
+    psh 3
+    les
+    jnz while$0
+end_while$0:
+"
+        );
+        let code = program.dump_code();
+        assert!(code.contains("\nwhile$0:\n    # line 3\n    sap 0\n    pfa\n"));
+        assert!(code.ends_with("    jnz while$0\nend_while$0:\n"));
+    }
+
+    #[test]
+    fn format() {
+        let source = "a=1 # one\n\n\n\nwhile a<3{\n  a=a+1\n\tif not a=(2){\nprint(-(a+1),a-(1-2))\n}\n}\nfn  f( x,y ){\nreturn\n}\n/* two */ b *=  2*(a%3)\n";
+        assert_eq!(
+            super::format(source).unwrap(),
+            "a = 1 # one
+
+while a < 3 {
+    a += 1
+    if not a = 2 {
+        print(-(a + 1), a - (1 - 2))
+    }
+}
+fn f(x, y) {
+    return
+}
+/* two */ b *= 2 * (a % 3)
+"
+        );
+        assert_eq!(
+            super::format("a = 1e300 + 0.0000001 * 120000\nb = -(-a) + -(3)\n").unwrap(),
+            "a = 1e300 + 1e-7 * 1.2e5\nb = -(-a) + -3\n"
+        );
+        let error = super::format("a = 1\nb = = 2\n").unwrap_err();
+        assert_eq!(error.span.start.line, 2);
+    }
+
+    /// The statements of `source` without their spans.
+    fn statements(source: &str) -> Vec<crate::ast::Ast> {
+        match crate::ast::Ast::parse(source, true) {
+            Ok(crate::ast::Ast::Root(inner)) => inner.into_iter().map(|(_, inst)| inst).collect(),
+            _ => panic!("`{source}` does not parse"),
+        }
+    }
+
+    /// Random programs in untidy spacing, from a xorshift generator.
+    struct Programs(u64);
+
+    impl Programs {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len() as u64) as usize]
+        }
+
+        fn space(&mut self) -> &'static str {
+            self.pick(&["", " ", "  ", "\t"])
+        }
+
+        fn expression(&mut self, depth: u32) -> String {
+            let kinds = if depth == 0 { 3 } else { 10 };
+            match self.below(kinds) {
+                0 => self
+                    .pick(&["0", "1", "2.5", ".5", "10", "3e2", "1e999"])
+                    .to_string(),
+                1 => self.pick(&["a", "b", "c"]).to_string(),
+                2 => "input()".to_string(),
+                3 => format!("({})", self.expression(depth - 1)),
+                4 => format!("-{}", self.expression(depth - 1)),
+                5 => format!("abs({})", self.expression(depth - 1)),
+                6 => {
+                    let name = self.pick(&["max", "min", "f"]);
+                    let (lhs, rhs) = (self.expression(depth - 1), self.expression(depth - 1));
+                    format!("{name}({lhs},{}{rhs})", self.space())
+                }
+                _ => {
+                    let op = self.pick(&["+", "-", "*", "/", "%", "=", ">", "<"]);
+                    let (lhs, rhs) = (self.expression(depth - 1), self.expression(depth - 1));
+                    format!("{lhs}{}{op}{}{rhs}", self.space(), self.space())
+                }
+            }
+        }
+
+        fn statement(&mut self, depth: u32) -> String {
+            let e = self.expression(3);
+            let var = self.pick(&["a", "b", "c"]);
+            match self.below(if depth == 0 { 10 } else { 14 }) {
+                0 => format!("{var}{}={}{e}", self.space(), self.space()),
+                1 => format!("let {var} = {e}"),
+                2 => {
+                    let op = self.pick(&["+=", "-=", "*=", "/=", "%="]);
+                    format!("{var}{}{op}{}{e}", self.space(), self.space())
+                }
+                3 => format!("print({e}, {})", self.expression(2)),
+                4 => "swap a and b".to_string(),
+                5 => "l:".to_string(),
+                6 => format!("goto l if {e} = 0"),
+                7 => format!("goto l if {e} != 0"),
+                8 => format!("return {e}"),
+                9 => format!("f({e})"),
+                kind => {
+                    let head = match kind {
+                        10 => "while",
+                        11 => "if",
+                        12 => "fn",
+                        _ => "if not",
+                    };
+                    let mut block = match head {
+                        "fn" => "fn g(a, b) {\n".to_string(),
+                        head => format!("{head} {e}{}{{\n", self.space()),
+                    };
+                    self.block(depth - 1, &mut block);
+                    if head.starts_with("if") && self.below(2) == 0 {
+                        block += &format!("}} else if {}{{\n", self.expression(2));
+                        self.block(depth - 1, &mut block);
+                        block += "} else {\n";
+                        self.block(depth - 1, &mut block);
+                    }
+                    block + "}"
+                }
+            }
+        }
+
+        fn block(&mut self, depth: u32, out: &mut String) {
+            for _ in 0..self.below(4) {
+                *out += self.space();
+                match self.below(8) {
+                    0 => *out += "// note\n",
+                    1 => *out += "\n\n",
+                    2 => *out += "/* before */ ",
+                    _ => (),
+                }
+                *out += &self.statement(depth);
+                if self.below(4) == 0 {
+                    *out += " # after";
+                }
+                *out += "\n";
+            }
+        }
+    }
+
+    #[test]
+    fn format_round_trips() {
+        let mut programs = Programs(0x2545_f491_4f6c_dd1d);
+        let mut parsed = 0;
+        for _ in 0..500 {
+            let mut source = String::new();
+            programs.block(3, &mut source);
+            if crate::ast::Ast::parse(&source, true).is_err() {
+                continue;
+            }
+            parsed += 1;
+            let formatted = super::format(&source).unwrap();
+            assert!(
+                statements(&formatted) == statements(&source),
+                "{source}\nformats to\n{formatted}"
+            );
+            assert_eq!(super::format(&formatted).unwrap(), formatted);
+        }
+        assert!(parsed > 400, "only {parsed} programs parse");
+    }
 }
@@ -0,0 +1,136 @@
+use std::collections::{BTreeSet, HashMap};
+
+use super::{
+    cfg::successors,
+    diagnostic::{Diagnostic, Span},
+    ir::{Ir, IrInst, MEMORY_SIZE},
+    Stats,
+};
+
+impl IrInst {
+    /// Slots the instruction reads.
+    fn uses(&self) -> Vec<usize> {
+        match self {
+            IrInst::Pfa(id) => vec![*id],
+            IrInst::Swap(id0, id1) => vec![*id0, *id1],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Slots the instruction writes.
+    fn defs(&self) -> Vec<usize> {
+        match self {
+            IrInst::Pta(id) => vec![*id],
+            IrInst::Swap(id0, id1) => vec![*id0, *id1],
+            _ => Vec::new(),
+        }
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut usize> {
+        match self {
+            IrInst::Pfa(id) | IrInst::Pta(id) => vec![id],
+            IrInst::Swap(id0, id1) => vec![id0, id1],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Ir {
+    /// Gives slots that are never live at the same time the same address, in the order the
+    /// slots first show up, and fails if the result does not fit into the VM memory.
+    pub(super) fn allocate(&mut self) -> Result<Stats, Vec<Diagnostic>> {
+        let live_out = self.live_out();
+        let mut order = Vec::new();
+        let mut interference: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+        for inst in &self.0 {
+            for id in inst.uses().into_iter().chain(inst.defs()) {
+                interference.entry(id).or_insert_with(|| {
+                    order.push(id);
+                    BTreeSet::new()
+                });
+            }
+        }
+        for (inst, out) in self.0.iter().zip(&live_out) {
+            let defs = inst.defs();
+            for def in &defs {
+                for other in out.iter().chain(&defs).filter(|other| *other != def) {
+                    interference.entry(*def).or_default().insert(*other);
+                    interference.entry(*other).or_default().insert(*def);
+                }
+            }
+        }
+
+        let mut addresses = HashMap::new();
+        for id in &order {
+            let taken: BTreeSet<usize> = interference[id]
+                .iter()
+                .filter_map(|other| addresses.get(other).copied())
+                .collect();
+            let mut address = 0;
+            while taken.contains(&address) {
+                address += 1;
+            }
+            addresses.insert(*id, address);
+        }
+
+        let mut span = Span::default();
+        let mut overflow = None;
+        for inst in &mut self.0 {
+            if let IrInst::Line(Some(line)) = inst {
+                span = *line;
+            }
+            for id in inst.slots_mut() {
+                *id = addresses[id];
+                if *id >= MEMORY_SIZE && overflow.is_none() {
+                    overflow = Some(span);
+                }
+            }
+        }
+        if let Some(span) = overflow {
+            return Err(vec![Diagnostic::error(
+                span,
+                format!("program needs more than {MEMORY_SIZE} memory cells"),
+            )]);
+        }
+        Ok(Stats {
+            variables: order.len(),
+            slots: addresses.values().max().map_or(0, |max| max + 1),
+        })
+    }
+
+    fn live_out(&self) -> Vec<BTreeSet<usize>> {
+        let uses: Vec<_> = self.0.iter().map(IrInst::uses).collect();
+        let defs: Vec<_> = self.0.iter().map(IrInst::defs).collect();
+        live_out(&successors(&self.0, IrInst::flow), &uses, &defs)
+    }
+}
+
+/// Slots that may still be read after each instruction, given the instructions that can run
+/// next and the slots every instruction reads and writes.
+pub(super) fn live_out(
+    successors: &[Vec<usize>],
+    uses: &[Vec<usize>],
+    defs: &[Vec<usize>],
+) -> Vec<BTreeSet<usize>> {
+    let mut live_in = vec![BTreeSet::new(); successors.len()];
+    let mut live_out = vec![BTreeSet::new(); successors.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..successors.len()).rev() {
+            let out: BTreeSet<usize> = successors[i]
+                .iter()
+                .flat_map(|next| live_in[*next].iter().copied())
+                .collect();
+            let mut live = out.clone();
+            defs[i].iter().for_each(|id| _ = live.remove(id));
+            live.extend(&uses[i]);
+            if live != live_in[i] {
+                live_in[i] = live;
+                changed = true;
+            }
+            live_out[i] = out;
+        }
+    }
+    live_out
+}
@@ -0,0 +1,232 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    cfg::{labels, successors, Flow},
+    ir::IrInst2,
+    liveness::live_out,
+};
+
+/// Rewrites short sequences of instructions into cheaper ones that do the same, until none
+/// are left. Runs before labels are resolved, so removing instructions is free, and leaves the
+/// markers of source lines where they are.
+pub(super) fn optimise(prog: &mut Vec<IrInst2>) {
+    let mut changed = true;
+    while changed {
+        changed = jumps(prog);
+        changed |= unused_labels(prog);
+        changed |= redundant_saps(prog);
+        changed |= dead_stores(prog);
+        changed |= discarded_values(prog);
+        changed |= dead_saps(prog);
+    }
+}
+
+impl IrInst2 {
+    fn flow(&self) -> Flow<'_> {
+        match self {
+            IrInst2::Label(id) => Flow::Label(id),
+            IrInst2::Jmp(id) => Flow::Jump(id),
+            IrInst2::Jiz(id) | IrInst2::Jnz(id) => Flow::Branch(id),
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// Follows jumps that land on another `Jmp`, and drops jumps to the instruction right after
+/// them. A conditional jump still has to throw its condition away.
+fn jumps(prog: &mut Vec<IrInst2>) -> bool {
+    let labels = labels(prog, IrInst2::flow);
+    let mut targets = HashMap::new();
+    for (i, inst) in prog.iter().enumerate() {
+        let (IrInst2::Jmp(id) | IrInst2::Jiz(id) | IrInst2::Jnz(id)) = inst else {
+            continue;
+        };
+        let mut target = id.as_str();
+        let mut seen = HashSet::from([target]);
+        while let Some(IrInst2::Jmp(next)) = prog[labels[target]..]
+            .iter()
+            .find(|inst| !matches!(inst, IrInst2::Label(_) | IrInst2::Line(_)))
+        {
+            if !seen.insert(next) {
+                break;
+            }
+            target = next;
+        }
+        let next = prog[i + 1..]
+            .iter()
+            .take_while(|inst| matches!(inst, IrInst2::Label(_) | IrInst2::Line(_)))
+            .any(|inst| matches!(inst, IrInst2::Label(id) if id == target));
+        targets.insert(i, (target.to_string(), next));
+    }
+    let mut changed = false;
+    let old = std::mem::take(prog);
+    for (i, inst) in old.into_iter().enumerate() {
+        let Some((target, next)) = targets.remove(&i) else {
+            prog.push(inst);
+            continue;
+        };
+        match inst {
+            IrInst2::Jmp(_) if next => changed = true,
+            IrInst2::Jiz(_) | IrInst2::Jnz(_) if next => {
+                prog.push(IrInst2::Pop);
+                changed = true
+            }
+            IrInst2::Jmp(id) if id != target => {
+                prog.push(IrInst2::Jmp(target));
+                changed = true
+            }
+            IrInst2::Jiz(id) if id != target => {
+                prog.push(IrInst2::Jiz(target));
+                changed = true
+            }
+            IrInst2::Jnz(id) if id != target => {
+                prog.push(IrInst2::Jnz(target));
+                changed = true
+            }
+            inst => prog.push(inst),
+        }
+    }
+    changed
+}
+
+fn unused_labels(prog: &mut Vec<IrInst2>) -> bool {
+    let used: HashSet<String> = prog
+        .iter()
+        .filter_map(|inst| match inst {
+            IrInst2::Jmp(id) | IrInst2::Jiz(id) | IrInst2::Jnz(id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect();
+    let len = prog.len();
+    prog.retain(|inst| !matches!(inst, IrInst2::Label(id) if !used.contains(id)));
+    prog.len() != len
+}
+
+/// Drops a `Sap` when the address is already selected. Nothing is known about the address
+/// at a label, as it can be reached from anywhere.
+fn redundant_saps(prog: &mut Vec<IrInst2>) -> bool {
+    let mut address = None;
+    let len = prog.len();
+    prog.retain(|inst| match inst {
+        IrInst2::Sap(id) => {
+            let keep = address != Some(*id);
+            address = Some(*id);
+            keep
+        }
+        IrInst2::Label(_) => {
+            address = None;
+            true
+        }
+        _ => true,
+    });
+    prog.len() != len
+}
+
+/// Drops a `Sap` when the address is selected again, or the code jumps away, before anything
+/// uses it.
+fn dead_saps(prog: &mut Vec<IrInst2>) -> bool {
+    let mut needed = false;
+    let mut dead = HashSet::new();
+    for (i, inst) in prog.iter().enumerate().rev() {
+        match inst {
+            IrInst2::Pfa | IrInst2::Pta => needed = true,
+            IrInst2::Sap(_) => {
+                if !needed {
+                    dead.insert(i);
+                }
+                needed = false
+            }
+            IrInst2::Label(_) | IrInst2::Jmp(_) => needed = false,
+            _ => (),
+        }
+    }
+    remove(prog, &dead)
+}
+
+/// A store right before a load of the same cell leaves the value on the stack anyway, so
+/// both go away when nothing reads the cell afterwards.
+fn dead_stores(prog: &mut Vec<IrInst2>) -> bool {
+    let Some(addresses) = addresses(prog) else {
+        return false;
+    };
+    let successors = successors(prog, IrInst2::flow);
+    let accesses = |kind: fn(&IrInst2) -> bool| -> Vec<Vec<usize>> {
+        prog.iter()
+            .zip(&addresses)
+            .map(|(inst, address)| match address {
+                Some(id) if kind(inst) => vec![*id],
+                _ => Vec::new(),
+            })
+            .collect()
+    };
+    let uses = accesses(|inst| matches!(inst, IrInst2::Pfa));
+    let defs = accesses(|inst| matches!(inst, IrInst2::Pta));
+    let live_out = live_out(&successors, &uses, &defs);
+
+    let mut dead = HashSet::new();
+    for (i, inst) in prog.iter().enumerate() {
+        if !matches!(inst, IrInst2::Pta) {
+            continue;
+        }
+        let load = match next(prog, i) {
+            Some(sap) if matches!(prog[sap], IrInst2::Sap(_)) => next(prog, sap),
+            load => load,
+        };
+        let Some(load) = load else {
+            continue;
+        };
+        if matches!(prog[load], IrInst2::Pfa)
+            && addresses[load] == addresses[i]
+            && addresses[i].is_some_and(|id| !live_out[load].contains(&id))
+        {
+            dead.extend([i, load]);
+        }
+    }
+    remove(prog, &dead)
+}
+
+/// Drops values that are pushed only to be popped right away.
+fn discarded_values(prog: &mut Vec<IrInst2>) -> bool {
+    let mut dead = HashSet::new();
+    for (i, inst) in prog.iter().enumerate() {
+        let Some(pop) = next(prog, i) else {
+            continue;
+        };
+        if matches!(inst, IrInst2::Psh(_) | IrInst2::Pfa) && matches!(prog[pop], IrInst2::Pop) {
+            dead.extend([i, pop]);
+        }
+    }
+    remove(prog, &dead)
+}
+
+/// Index of the instruction that runs after `prog[i]` when it does not jump, skipping the
+/// markers of source lines.
+fn next(prog: &[IrInst2], i: usize) -> Option<usize> {
+    (i + 1..prog.len()).find(|&i| !matches!(prog[i], IrInst2::Line(_)))
+}
+
+/// Address selected when each instruction runs, `None` if any `Pfa` or `Pta` cannot be
+/// traced back to a `Sap` of its own block.
+fn addresses(prog: &[IrInst2]) -> Option<Vec<Option<usize>>> {
+    let mut address = None;
+    let mut addresses = Vec::new();
+    for inst in prog {
+        match inst {
+            IrInst2::Sap(id) => address = Some(*id),
+            IrInst2::Label(_) => address = None,
+            IrInst2::Pfa | IrInst2::Pta if address.is_none() => return None,
+            _ => (),
+        }
+        addresses.push(address);
+    }
+    Some(addresses)
+}
+
+fn remove(prog: &mut Vec<IrInst2>, dead: &HashSet<usize>) -> bool {
+    let mut i = 0;
+    prog.retain(|_| {
+        i += 1;
+        !dead.contains(&(i - 1))
+    });
+    !dead.is_empty()
+}
@@ -0,0 +1,178 @@
+use std::{error, fmt, time::Duration};
+
+use mpl_vm::Instructions;
+
+use super::diagnostic::Span;
+
+/// Error of `mpl_vm`, like running out of input or dividing by zero.
+pub use mpl_vm::ProgramError as VmError;
+
+/// Error that stopped a running program.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    /// index of the instruction that was running
+    pub index: usize,
+    /// source line of the instruction, `None` for code the compiler adds on its own
+    pub span: Option<Span>,
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// the VM failed on its own
+    Vm(VmError),
+    /// the program went past one of its `Limits`
+    LimitExceeded(Limit),
+}
+
+/// Bounds on what a program may do while it runs, `None` leaves that part unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// instructions run
+    pub steps: Option<u64>,
+    /// wall time since the program started
+    pub time: Option<Duration>,
+    /// values read with `input()`
+    pub inputs: Option<usize>,
+    /// values printed
+    pub outputs: Option<usize>,
+}
+
+/// One of the `Limits`, with the bound that was set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    Steps(u64),
+    Time(Duration),
+    Inputs(usize),
+    Outputs(usize),
+}
+
+impl RuntimeError {
+    pub(crate) fn new(kind: ErrorKind, index: usize, span: Option<Span>) -> RuntimeError {
+        RuntimeError { kind, index, span }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Steps(steps) => write!(f, "ran more than {steps} instructions"),
+            Limit::Time(time) => write!(f, "ran longer than {time:?}"),
+            Limit::Inputs(inputs) => write!(f, "read more than {inputs} inputs"),
+            Limit::Outputs(outputs) => write!(f, "printed more than {outputs} values"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Vm(error) => write!(f, "runtime error: {error:?}"),
+            ErrorKind::LimitExceeded(limit) => write!(f, "limit exceeded: program {limit}"),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}: {}", span.start, self.kind),
+            None => write!(f, "instruction {}: {}", self.index, self.kind),
+        }
+    }
+}
+
+impl error::Error for RuntimeError {}
+
+/// Follows the program counter of `mpl_vm`, which runs a program without telling where it
+/// is. The VM runs the code from `Tracer::probed`, with a `pek` in front of every conditional
+/// jump, so that the value the jump tests comes out of the VM like a printed value. All that
+/// is left to do here is following the jumps, no value is computed on the side. This relies on
+/// `mpl_vm::Program` yielding once for every instruction it runs.
+pub(crate) struct Tracer {
+    code: Vec<Instructions>,
+    /// index in `code` of the instruction that runs next
+    pub(crate) pc: usize,
+    /// value the conditional jump at `pc` tests, once its `pek` ran
+    test: Option<f64>,
+}
+
+impl Tracer {
+    pub(crate) fn new(code: Vec<Instructions>) -> Tracer {
+        Tracer {
+            code,
+            pc: 0,
+            test: None,
+        }
+    }
+
+    /// The code for the VM, with a `pek` in front of every conditional jump and the targets
+    /// of the jumps moved along.
+    pub(crate) fn probed(&self) -> Vec<Instructions> {
+        // index of every instruction in the probed code, and of the end of the code
+        let mut moved = Vec::new();
+        let mut probes = 0;
+        for inst in &self.code {
+            moved.push(moved.len() + probes);
+            probes += usize::from(matches!(inst, Instructions::Jiz(_) | Instructions::Jnz(_)));
+        }
+        moved.push(moved.len() + probes);
+        let mut code = Vec::new();
+        for inst in &self.code {
+            if matches!(inst, Instructions::Jiz(_) | Instructions::Jnz(_)) {
+                code.push(Instructions::Pek);
+            }
+            code.push(match inst {
+                Instructions::Jmp(target) => Instructions::Jmp(moved[*target]),
+                Instructions::Jiz(target) => Instructions::Jiz(moved[*target]),
+                Instructions::Jnz(target) => Instructions::Jnz(moved[*target]),
+                Instructions::Psh(val) => Instructions::Psh(*val),
+                Instructions::Pop => Instructions::Pop,
+                Instructions::Pek => Instructions::Pek,
+                Instructions::Inp => Instructions::Inp,
+                Instructions::Sap(id) => Instructions::Sap(*id),
+                Instructions::Pfa => Instructions::Pfa,
+                Instructions::Pta => Instructions::Pta,
+                Instructions::Add => Instructions::Add,
+                Instructions::Sub => Instructions::Sub,
+                Instructions::Mul => Instructions::Mul,
+                Instructions::Div => Instructions::Div,
+                Instructions::Mod => Instructions::Mod,
+                Instructions::Abs => Instructions::Abs,
+                Instructions::Max => Instructions::Max,
+                Instructions::Min => Instructions::Min,
+                Instructions::Eql => Instructions::Eql,
+                Instructions::Mor => Instructions::Mor,
+                Instructions::Les => Instructions::Les,
+            });
+        }
+        code
+    }
+
+    /// Whether the VM runs one of the added `pek`s next, rather than an instruction of the
+    /// program.
+    pub(crate) fn probing(&self) -> bool {
+        self.test.is_none()
+            && matches!(
+                self.code.get(self.pc),
+                Some(Instructions::Jiz(_) | Instructions::Jnz(_))
+            )
+    }
+
+    /// Moves on by one instruction the VM ran without an error, given what it yielded, and
+    /// gives back the value the program printed, if any.
+    pub(crate) fn step(&mut self, val: Option<f64>) -> Option<f64> {
+        if self.probing() {
+            self.test = val;
+            return None;
+        }
+        let test = self.test.take();
+        self.pc = match self.code.get(self.pc) {
+            Some(Instructions::Jmp(target)) => *target,
+            Some(Instructions::Jiz(target)) if test == Some(0.0) => *target,
+            Some(Instructions::Jnz(target)) if test.is_some_and(|val| val != 0.0) => *target,
+            _ => self.pc + 1,
+        };
+        val
+    }
+}
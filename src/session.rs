@@ -0,0 +1,139 @@
+use std::{error, fmt};
+
+use super::{Diagnostic, Limits, Options, Parser, RuntimeError};
+
+/// Runs one snippet after another, keeping the variables of the outermost scope in between.
+/// Functions and labels only live as long as the snippet that defines them.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    vars: Vec<(String, f64)>,
+    options: Options,
+    limits: Limits,
+    warnings: Vec<Diagnostic>,
+}
+
+/// Why a snippet of a `Session` did not run to the end.
+#[derive(Debug)]
+pub enum SessionError {
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl Session {
+    pub fn new(options: impl Into<Options>) -> Session {
+        Session {
+            options: options.into(),
+            ..Session::default()
+        }
+    }
+
+    /// Bounds every later snippet.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Compiles `source` against the variables so far without running it.
+    pub fn compile(&self, source: &str) -> Result<Parser, Vec<Diagnostic>> {
+        Parser::compile(source, self.options, Some(&self.vars))
+    }
+
+    /// Forgets every variable.
+    pub fn reset(&mut self) {
+        self.vars.clear();
+        self.warnings.clear();
+    }
+
+    /// Compiles `source` against the variables so far and runs it, handing every value it
+    /// prints to `output`. The variables only change when the snippet runs to the end.
+    pub fn eval_with<I, O>(&mut self, source: &str, input: I, output: O) -> Result<(), SessionError>
+    where
+        I: FnMut() -> Option<f64>,
+        O: FnMut(f64),
+    {
+        let mut program = self.compile(source)?;
+        self.eval_program(&mut program, input, output)
+    }
+
+    /// Runs `program`, which came from `compile`, like `eval_with` does, so that it is not
+    /// compiled a second time.
+    pub fn eval_program<I, O>(
+        &mut self,
+        program: &mut Parser,
+        input: I,
+        output: O,
+    ) -> Result<(), SessionError>
+    where
+        I: FnMut() -> Option<f64>,
+        O: FnMut(f64),
+    {
+        program.set_limits(self.limits);
+        self.warnings = program.warnings.clone();
+        let vals = program.execute(input, output, false)?;
+        self.vars = program.globals.iter().cloned().zip(vals).collect();
+        Ok(())
+    }
+
+    /// Runs `source` on `inputs` and collects what it prints.
+    pub fn run_collect(&mut self, source: &str, inputs: &[f64]) -> Result<Vec<f64>, SessionError> {
+        let mut inputs = inputs.iter().copied();
+        let mut outputs = Vec::new();
+        self.eval_with(source, || inputs.next(), |val| outputs.push(val))?;
+        Ok(outputs)
+    }
+
+    /// Warnings of the last snippet that compiled.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<f64> {
+        self.vars
+            .iter()
+            .find(|(var, _)| var == name)
+            .map(|(_, val)| *val)
+    }
+
+    /// Sets a variable for the snippets that follow, defining it if there is none yet.
+    pub fn set_var(&mut self, name: &str, val: f64) {
+        match self.vars.iter_mut().find(|(var, _)| var == name) {
+            Some((_, old)) => *old = val,
+            None => self.vars.push((name.to_string(), val)),
+        }
+    }
+
+    /// Every variable with its value, in the order they were defined.
+    pub fn vars(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.vars.iter().map(|(name, val)| (name.as_str(), *val))
+    }
+}
+
+impl From<Vec<Diagnostic>> for SessionError {
+    fn from(diagnostics: Vec<Diagnostic>) -> SessionError {
+        SessionError::Compile(diagnostics)
+    }
+}
+
+impl From<RuntimeError> for SessionError {
+    fn from(error: RuntimeError) -> SessionError {
+        SessionError::Runtime(error)
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Compile(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{diagnostic}")?;
+                }
+                Ok(())
+            }
+            SessionError::Runtime(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl error::Error for SessionError {}
@@ -0,0 +1,46 @@
+use super::diagnostic::Span;
+
+/// Statement every instruction of a compiled program comes from. Spans cover whole
+/// statements, the instructions of an expression all map to the statement it is in. Code the
+/// compiler adds on its own, like the dispatcher that returns from functions, maps to none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    spans: Vec<Option<Span>>,
+}
+
+impl SourceMap {
+    pub(crate) fn new(spans: Vec<Option<Span>>) -> SourceMap {
+        SourceMap { spans }
+    }
+
+    /// Source range of the instruction at `index`.
+    pub fn span(&self, index: usize) -> Option<Span> {
+        self.spans.get(index).copied().flatten()
+    }
+
+    /// Indices of the instructions of the statement that starts on `line`, for breakpoints
+    /// and the like.
+    pub fn instructions(&self, line: usize) -> Vec<usize> {
+        self.iter()
+            .filter(|(_, span)| span.start.line == line)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Every instruction with a source range, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, Span)> + '_ {
+        self.spans
+            .iter()
+            .enumerate()
+            .filter_map(|(index, span)| Some((index, (*span)?)))
+    }
+
+    /// Number of instructions of the program.
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}